@@ -0,0 +1,180 @@
+use std::fs;
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use drain3::SearchStrategy;
+use drain3::drain::{Drain, DrainConfig};
+
+const MESSAGE: &str = "session opened for user alice";
+
+/// The SSH sample corpus fetched by the demo, see `examples/sample_logs`.
+const SSH_LOG: &str = "examples/data/SSH.log";
+
+/// Lines of the corpus mined per iteration.
+const CORPUS_LINES: usize = 100_000;
+
+/// Message templates of the SSH sample corpus, with `{user}`, `{ip}`, `{port}` and
+/// `{n}` standing for its variable parts.
+const SSH_TEMPLATES: &[&str] = &[
+    "reverse mapping checking getaddrinfo for {user}.example.com [{ip}] failed - POSSIBLE BREAK-IN ATTEMPT!",
+    "Invalid user {user} from {ip}",
+    "input_userauth_request: invalid user {user} [preauth]",
+    "pam_unix(sshd:auth): check pass; user unknown",
+    "pam_unix(sshd:auth): authentication failure; logname= uid=0 euid=0 tty=ssh ruser= rhost={ip}",
+    "pam_unix(sshd:auth): authentication failure; logname= uid=0 euid=0 tty=ssh ruser= rhost={ip}  user=root",
+    "Failed password for invalid user {user} from {ip} port {port} ssh2",
+    "Failed password for root from {ip} port {port} ssh2",
+    "message repeated {n} times: [ Failed password for root from {ip} port {port} ssh2]",
+    "PAM {n} more authentication failures; logname= uid=0 euid=0 tty=ssh ruser= rhost={ip}  user=root",
+    "Disconnecting: Too many authentication failures for root [preauth]",
+    "Received disconnect from {ip}: 11: Bye Bye [preauth]",
+    "Connection closed by {ip} [preauth]",
+    "Did not receive identification string from {ip}",
+    "Accepted password for {user} from {ip} port {port} ssh2",
+    "pam_unix(sshd:session): session opened for user {user} by (uid=0)",
+    "pam_unix(sshd:session): session closed for user {user}",
+];
+
+const SSH_USERS: &[&str] = &[
+    "admin",
+    "test",
+    "oracle",
+    "guest",
+    "webmaster",
+    "postgres",
+    "ftpuser",
+    "support",
+    "ubnt",
+    "pi",
+    "git",
+    "user",
+    "nagios",
+    "mysql",
+    "fztu",
+];
+
+/// The message contents of the SSH sample corpus if it was downloaded, else lines
+/// generated from its templates with a fixed seed.
+fn ssh_corpus() -> (&'static str, Vec<String>) {
+    if let Ok(log) = fs::read_to_string(SSH_LOG) {
+        let lines = log
+            .lines()
+            .take(CORPUS_LINES)
+            .map(|line| line.split_once("]: ").map_or(line, |(_, content)| content))
+            .map(str::to_string)
+            .collect();
+        return ("SSH.log", lines);
+    }
+
+    let mut seed: u64 = 0x5eed;
+    let mut next = |bound: usize| {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 33) as usize % bound
+    };
+    let lines = (0..CORPUS_LINES)
+        .map(|_| {
+            let ip = format!(
+                "{}.{}.{}.{}",
+                next(223) + 1,
+                next(256),
+                next(256),
+                next(256)
+            );
+            SSH_TEMPLATES[next(SSH_TEMPLATES.len())]
+                .replace("{user}", SSH_USERS[next(SSH_USERS.len())])
+                .replace("{ip}", &ip)
+                .replace("{port}", &(next(64_511) + 1024).to_string())
+                .replace("{n}", &(next(5) + 1).to_string())
+        })
+        .collect();
+    ("ssh_templates", lines)
+}
+
+fn new_drain() -> Drain {
+    Drain::new(&DrainConfig {
+        log_cluster_depth: 4,
+        sim_th: 0.4,
+        sim_th_by_length: vec![],
+        adaptive_sim_th: false,
+        max_children: 100,
+        max_clusters: None,
+        extra_delimiters: vec![],
+        parametrize_numeric_tokens: true,
+        routing_predicates: vec![],
+        max_length_difference: 0,
+        template_split: None,
+        cluster_merge: None,
+        value_stats: None,
+        template_history: 0,
+        token_prefix: "<".to_string(),
+        token_suffix: ">".to_string(),
+        token_template: "TOKEN".to_string(),
+    })
+}
+
+/// A drain with the message's cluster and `clusters` others, sharing its leaf of
+/// the prefix tree if `in_leaf`, else in other branches and of other lengths.
+fn drain_with_clusters(clusters: usize, in_leaf: bool) -> Drain {
+    let mut drain = new_drain();
+    drain.add_log_message(MESSAGE);
+    for i in 0..clusters {
+        // Digit-free names, so that they are not routed to wildcards.
+        let name: String = format!("{:x}", i)
+            .chars()
+            .map(|c| (b'g' + c.to_digit(16).unwrap() as u8) as char)
+            .collect();
+        if in_leaf {
+            drain.add_log_message(&format!("session {} {} {} {}", name, name, name, name));
+        } else if i % 2 == 0 {
+            drain.add_log_message(&format!("{} opened for user alice", name));
+        } else {
+            drain.add_log_message(&format!("{}{}", MESSAGE, " again".repeat(i + 1)));
+        }
+    }
+    drain
+}
+
+/// Fast matching follows one path of token ids down the tree without allocating
+/// per node or cluster, so its time should stay flat as other branches grow, and
+/// only grow with the clusters it scores in the message's leaf. Full matching also
+/// visits the other branches of the message's length.
+fn bench_match_cluster(c: &mut Criterion) {
+    for (name, in_leaf) in [("other_branches", false), ("same_leaf", true)] {
+        let mut group = c.benchmark_group(format!("match_cluster/{}", name));
+        for clusters in [0, 100, 1000] {
+            let drain = drain_with_clusters(clusters, in_leaf);
+            group.bench_with_input(BenchmarkId::new("fast", clusters), &drain, |b, drain| {
+                b.iter(|| drain.match_cluster(MESSAGE, SearchStrategy::Fast))
+            });
+            group.bench_with_input(BenchmarkId::new("full", clusters), &drain, |b, drain| {
+                b.iter(|| drain.match_cluster(MESSAGE, SearchStrategy::Full))
+            });
+        }
+        group.finish();
+    }
+}
+
+/// Mining throughput in lines per second over the SSH corpus, from an empty drain.
+fn bench_mine_corpus(c: &mut Criterion) {
+    let (name, lines) = ssh_corpus();
+    let mut group = c.benchmark_group("mine");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(lines.len() as u64));
+    group.bench_function(name, |b| {
+        b.iter_batched(
+            new_drain,
+            |mut drain| {
+                for line in &lines {
+                    drain.add_log_message(line);
+                }
+                drain
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_match_cluster, bench_mine_corpus);
+criterion_main!(benches);
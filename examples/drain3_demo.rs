@@ -122,25 +122,20 @@ fn main() -> anyhow::Result<()> {
             continue;
         }
 
-        // let content = if let Some(idx) = line.find(": ") {
-        //     &line[idx + 2..]
-        // } else {
-        //     line
-        // };
-        let content = line;
+        let content = miner.parse_log_line(line).content;
 
-        let (cluster, update_type) = miner.add_log_message(content);
+        let (cluster, update_type) = miner.add_log_message(&content);
 
         let entry = sample_lines
             .entry(cluster.unwrap().lock().unwrap().get_cluster_id())
-            .or_insert_with(Vec::new);
+            .or_default();
 
         let exists = entry.iter().any(|sl| sl.update_type == update_type);
 
         if !exists {
             entry.push(SampleLine {
                 line: line_num,
-                content: content.to_string(),
+                content,
                 update_type,
             });
         }
@@ -149,7 +144,7 @@ fn main() -> anyhow::Result<()> {
         if config.max_lines > 0 && line_count >= config.max_lines {
             break;
         }
-        if line_count % 10000 == 0 {
+        if line_count.is_multiple_of(10000) {
             let now = Instant::now();
             let batch_duration = now.duration_since(batch_start);
             let batch_lines_sec = 10000.0 / batch_duration.as_secs_f64();
@@ -163,15 +158,14 @@ fn main() -> anyhow::Result<()> {
 
     if config.enable_profiler
         && let Some(g) = guard
+        && let Ok(report) = g.report().build()
     {
-        if let Ok(report) = g.report().build() {
-            let path = "examples/outputs/flamegraph.svg";
-            let file = File::create(path).unwrap();
-            let mut options = pprof::flamegraph::Options::default();
-            options.image_width = Some(3000);
-            report.flamegraph_with_options(file, &mut options).unwrap();
-            println!("flamegraph saved to {}", path);
-        }
+        let path = "examples/outputs/flamegraph.svg";
+        let file = File::create(path).unwrap();
+        let mut options = pprof::flamegraph::Options::default();
+        options.image_width = Some(3000);
+        report.flamegraph_with_options(file, &mut options).unwrap();
+        println!("flamegraph saved to {}", path);
     }
 
     let duration = start.elapsed();
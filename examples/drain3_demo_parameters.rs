@@ -56,11 +56,8 @@ fn main() -> anyhow::Result<()> {
             continue;
         }
 
-        let content = if let Some(idx) = line.find(": ") {
-            &line[idx + 2..]
-        } else {
-            line
-        };
+        let content = miner.parse_log_line(line).content;
+        let content = content.as_str();
 
         let log_cluster = match miner.match_cluster(content, SearchStrategy::Fallback) {
             Some(cluster) => cluster,
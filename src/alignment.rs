@@ -0,0 +1,138 @@
+use crate::interner::{TokenId, TokenInterner};
+
+/// One step aligning a template against a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AlignOp {
+    /// Equal tokens, or a parameter matching a message token.
+    Match,
+    /// Differing tokens at the same position.
+    Substitute,
+    /// A variable-length wildcard consumes a message token.
+    Absorb,
+    /// A variable-length wildcard ends.
+    Skip,
+    /// A message token with no template counterpart.
+    Insert,
+    /// A template token with no message counterpart.
+    Delete,
+}
+
+#[derive(Debug)]
+pub(crate) struct Alignment {
+    pub ops: Vec<AlignOp>,
+    /// Literal template tokens matched by equal message tokens.
+    pub literal: usize,
+}
+
+impl Alignment {
+    /// Whether the message fits the template as is.
+    pub fn is_exact(&self) -> bool {
+        self.ops
+            .iter()
+            .all(|op| matches!(op, AlignOp::Match | AlignOp::Absorb | AlignOp::Skip))
+    }
+
+    /// Literal matches over the longer of the message and the template's
+    /// fixed-length part. Parameters get no credit, so that a template mostly made
+    /// of them doesn't pass for anything of nearby length.
+    pub fn similarity(&self, template_len: usize, message_len: usize) -> f64 {
+        let len = template_len.max(message_len);
+        if len == 0 {
+            return 1.0;
+        }
+        self.literal as f64 / len as f64
+    }
+}
+
+/// Score of aligning a template suffix with a message suffix, as (literal,
+/// matched, -gaps), so comparing tuples prefers fewer gaps on equal matches.
+type Score = (usize, usize, isize);
+
+/// Tables of `align`, kept to be reused for the next candidate template.
+#[derive(Debug, Default)]
+pub(crate) struct AlignBuffers {
+    best: Vec<Score>,
+    step: Vec<AlignOp>,
+}
+
+/// Aligns a template with a message maximizing literal matches, then matched
+/// parameters, then minimizing insertions and deletions, in the manner of a
+/// longest common subsequence.
+pub(crate) fn align(
+    template: &[TokenId],
+    message: &[TokenId],
+    variable_token: TokenId,
+    interner: &TokenInterner,
+    buffers: &mut AlignBuffers,
+) -> Alignment {
+    let (n, m) = (template.len(), message.len());
+    let width = m + 1;
+    // best[i * width + j] scores aligning template[i..] with message[j..].
+    let AlignBuffers { best, step } = buffers;
+    best.clear();
+    best.resize((n + 1) * width, (0, 0, 0));
+    step.clear();
+    step.resize((n + 1) * width, AlignOp::Insert);
+
+    for i in (0..=n).rev() {
+        for j in (0..=m).rev() {
+            if i == n && j == m {
+                continue;
+            }
+
+            // The first of the best candidates wins ties.
+            let mut choice: Option<(AlignOp, Score)> = None;
+            let mut consider = |op: AlignOp, score: Score| {
+                if choice.is_none_or(|(_, best)| score > best) {
+                    choice = Some((op, score));
+                }
+            };
+            if i < n && template[i] == variable_token {
+                consider(AlignOp::Skip, best[(i + 1) * width + j]);
+                if j < m {
+                    consider(AlignOp::Absorb, best[i * width + j + 1]);
+                }
+            } else if i < n {
+                if j < m {
+                    let (literal, matched, gaps) = best[(i + 1) * width + j + 1];
+                    if interner.accepts(template[i], message[j]) {
+                        let literal = literal + usize::from(!interner.is_param(template[i]));
+                        consider(AlignOp::Match, (literal, matched + 1, gaps));
+                    } else {
+                        consider(AlignOp::Substitute, (literal, matched, gaps));
+                    }
+                }
+                let (literal, matched, gaps) = best[(i + 1) * width + j];
+                consider(AlignOp::Delete, (literal, matched, gaps - 1));
+            }
+            if j < m {
+                let (literal, matched, gaps) = best[i * width + j + 1];
+                consider(AlignOp::Insert, (literal, matched, gaps - 1));
+            }
+
+            let (op, score) = choice.unwrap();
+            best[i * width + j] = score;
+            step[i * width + j] = op;
+        }
+    }
+
+    let mut ops = Vec::with_capacity(n.max(m));
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        let op = step[i * width + j];
+        ops.push(op);
+        match op {
+            AlignOp::Match | AlignOp::Substitute => {
+                i += 1;
+                j += 1;
+            }
+            AlignOp::Skip | AlignOp::Delete => i += 1,
+            AlignOp::Absorb | AlignOp::Insert => j += 1,
+        }
+    }
+
+    Alignment {
+        ops,
+        literal: best[0].0,
+    }
+}
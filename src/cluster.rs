@@ -1,10 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{self, Write};
 use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
 use strum_macros::Display;
 
+use crate::alignment::AlignOp;
+use crate::drain::ADAPTIVE_SIM_TH_RANGE;
+use crate::interner::{STALE_PARAM, TokenId, TokenInterner};
+use crate::param_type::{ParamType, ParamTypeStats};
+use crate::tokenizer;
+use crate::value_stats::{PositionStats, ValueStatsConfig};
+
 static CLUSTER_MAP: LazyLock<Mutex<HashMap<usize, Arc<Mutex<LogCluster>>>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
 
@@ -19,13 +27,109 @@ pub enum UpdateType {
     None,
     Created,
     Updated,
+    /// The cluster was split into more specific templates, see `Drain::take_split_events`.
+    Split,
+}
+
+/// Distinct values seen at a wildcard position, until there are too many of them to
+/// be an enumeration of constants.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PositionValues {
+    pub counts: HashMap<String, usize>,
+    pub overflowed: bool,
 }
 
+impl PositionValues {
+    fn add(&mut self, value: &str, count: usize, limit: usize) {
+        if self.overflowed {
+            return;
+        }
+        match self.counts.get_mut(value) {
+            Some(c) => *c += count,
+            None => {
+                self.counts.insert(value.to_string(), count);
+            }
+        }
+        if self.counts.len() > limit {
+            self.overflowed = true;
+            self.counts.clear();
+        }
+    }
+}
+
+/// Running mean and variance of the similarity of messages matched to a cluster.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SimilarityStats {
+    pub count: usize,
+    pub mean: f64,
+    m2: f64,
+}
+
+impl SimilarityStats {
+    pub fn add(&mut self, similarity: f64) {
+        self.count += 1;
+        let delta = similarity - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (similarity - self.mean);
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        if self.count < 2 {
+            return 0.0;
+        }
+        (self.m2 / (self.count - 1) as f64).sqrt()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+/// A template a cluster had, recorded when a message or a merge changed it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TemplateVersion {
+    pub template: String,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Messages of the cluster at the time, the triggering one included.
+    pub size: usize,
+    /// The message that changed the template, `None` for merges and batch refinement.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Matches a cluster must observe before it uses its adaptive threshold.
+const ADAPTIVE_SIM_TH_MIN_SAMPLES: usize = 10;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogCluster {
     pub tokens: Vec<String>,
     pub cluster_id: usize,
     pub size: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_value_separator: Option<String>,
+    /// Partition the cluster was mined in, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partition: Option<String>,
+    /// Threshold derived from `similarity_stats` in adaptive mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sim_th: Option<f64>,
+    #[serde(default, skip_serializing_if = "SimilarityStats::is_empty")]
+    pub similarity_stats: SimilarityStats,
+    /// Values seen at each wildcard position, tracked when template splitting is on.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub position_values: BTreeMap<usize, PositionValues>,
+    /// Values seen at each wildcard position, summarized to infer their type.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub type_stats: BTreeMap<usize, ParamTypeStats>,
+    /// Statistics of the values at each wildcard position, when configured.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub value_stats: BTreeMap<usize, PositionStats>,
+    /// Templates the cluster had, oldest first, when a history is kept.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<TemplateVersion>,
+    #[serde(skip)]
+    pub(crate) token_ids: Vec<TokenId>,
 }
 
 impl LogCluster {
@@ -34,9 +138,28 @@ impl LogCluster {
             tokens: tokens.to_owned(),
             cluster_id,
             size: 1,
+            key_value_separator: None,
+            partition: None,
+            sim_th: None,
+            similarity_stats: SimilarityStats::default(),
+            position_values: BTreeMap::new(),
+            type_stats: BTreeMap::new(),
+            value_stats: BTreeMap::new(),
+            history: Vec::new(),
+            token_ids: Vec::new(),
         }
     }
 
+    pub(crate) fn with_token_ids(
+        tokens: &[String],
+        token_ids: &[TokenId],
+        cluster_id: usize,
+    ) -> Self {
+        let mut cluster = Self::new(tokens, cluster_id);
+        cluster.token_ids = token_ids.to_vec();
+        cluster
+    }
+
     pub fn get_tokens(&self) -> Vec<String> {
         self.tokens.clone()
     }
@@ -46,9 +169,82 @@ impl LogCluster {
     }
 
     pub fn get_template(&self) -> String {
-        self.tokens.join(" ")
+        tokenizer::join_tokens(&self.tokens, self.key_value_separator.as_deref())
+    }
+
+    /// Type inferred for the wildcard at `position`, if it has seen any values.
+    pub fn param_type(&self, position: usize) -> Option<ParamType> {
+        self.type_stats
+            .get(&position)
+            .map(ParamTypeStats::param_type)
+    }
+
+    /// Types inferred for the wildcard positions that have seen values.
+    pub fn param_types(&self) -> BTreeMap<usize, ParamType> {
+        self.type_stats
+            .iter()
+            .map(|(&position, stats)| (position, stats.param_type()))
+            .collect()
     }
 
+    /// Statistics of the values seen at the wildcard at `position`, if tracked.
+    pub fn position_stats(&self, position: usize) -> Option<&PositionStats> {
+        self.value_stats.get(&position)
+    }
+
+    /// Records `count` occurrences of `value` at the wildcard at `position`.
+    fn observe_value(
+        &mut self,
+        position: usize,
+        value: &str,
+        count: usize,
+        value_stats: Option<&ValueStatsConfig>,
+    ) {
+        self.type_stats
+            .entry(position)
+            .or_default()
+            .add(value, count);
+        if let Some(config) = value_stats {
+            self.value_stats
+                .entry(position)
+                .or_insert_with(|| PositionStats::new(config))
+                .add(value, count);
+        }
+    }
+
+    /// The template version in effect at `timestamp`, if the history goes back
+    /// that far.
+    pub fn template_at(&self, timestamp: u64) -> Option<&TemplateVersion> {
+        self.history
+            .iter()
+            .rev()
+            .find(|version| version.timestamp <= timestamp)
+    }
+
+    /// Records the current template as the latest version, keeping the `limit`
+    /// latest; a `limit` of 0 keeps no history.
+    pub(crate) fn record_version(&mut self, message: Option<&str>, limit: usize) {
+        if limit == 0 {
+            return;
+        }
+        self.history.push(TemplateVersion {
+            template: self.get_template(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            size: self.size,
+            message: message.map(str::to_string),
+        });
+        if self.history.len() > limit {
+            self.history.drain(..self.history.len() - limit);
+        }
+    }
+
+    /// Replaces every position where `tokens` differs from the template with
+    /// `get_next_token()`, unless `is_token` takes the template's token for a
+    /// parameter. In a drain's searches, positions changed here match any token
+    /// until the drain interns them on the cluster's next update.
     pub fn update_template<F1, F2>(
         &mut self,
         tokens: &[String],
@@ -59,27 +255,345 @@ impl LogCluster {
         F1: FnMut(&String) -> bool,
         F2: FnMut() -> String,
     {
-        let new_tokens = tokens
-            .iter()
-            .zip(self.tokens.iter())
-            .map(|(t1, t2)| {
-                if t1 == t2 || is_token(t2) {
-                    t2.clone()
-                } else {
-                    get_next_token()
-                }
-            })
-            .collect();
+        self.size += 1;
+
+        let mut updated = false;
+        for (i, token) in tokens.iter().enumerate().take(self.tokens.len()) {
+            if *token == self.tokens[i] || is_token(&self.tokens[i]) {
+                continue;
+            }
+            self.tokens[i] = get_next_token();
+            if let Some(token_id) = self.token_ids.get_mut(i) {
+                *token_id = STALE_PARAM;
+            }
+            updated = true;
+        }
 
+        if updated {
+            UpdateType::Updated
+        } else {
+            UpdateType::None
+        }
+    }
+
+    /// Replaces every position where `token_ids` differs from the template with a
+    /// new parameter token, unless the template already has one there. With a
+    /// `value_limit`, the values seen at parameter positions are counted, up to that
+    /// many distinct values per position.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn update_template_ids<F>(
+        &mut self,
+        tokens: &[String],
+        token_ids: &[TokenId],
+        interner: &mut TokenInterner,
+        value_limit: Option<usize>,
+        value_stats: Option<&ValueStatsConfig>,
+        mut get_next_token: F,
+    ) -> UpdateType
+    where
+        F: FnMut() -> String,
+    {
+        for (template_id, token) in self.token_ids.iter_mut().zip(&self.tokens) {
+            if *template_id == STALE_PARAM {
+                *template_id = interner.intern(token);
+            }
+        }
         self.size += 1;
-        if new_tokens != self.tokens {
-            self.tokens = new_tokens;
+
+        let mut updated = false;
+        for (i, &token_id) in token_ids.iter().enumerate().take(self.token_ids.len()) {
+            let template_id = self.token_ids[i];
+            if token_id == template_id {
+                continue;
+            }
+
+            if !interner.is_param(token_id) {
+                if !interner.is_param(template_id) {
+                    let template_token = self.tokens[i].clone();
+                    self.observe_value(i, &template_token, self.size - 1, value_stats);
+                }
+                self.observe_value(i, &tokens[i], 1, value_stats);
+            }
+
+            if let Some(limit) = value_limit
+                && !interner.is_param(token_id)
+            {
+                let values = self.position_values.entry(i).or_default();
+                if !interner.is_param(template_id) {
+                    // Every earlier message had the template's token here.
+                    values.add(&self.tokens[i], self.size - 1, limit);
+                }
+                values.add(&tokens[i], 1, limit);
+            }
+
+            if interner.is_wildcard(template_id) {
+                continue;
+            }
+
+            let token = get_next_token();
+            self.token_ids[i] = interner.intern(&token);
+            self.tokens[i] = token;
+            updated = true;
+        }
+
+        if updated {
             UpdateType::Updated
         } else {
             UpdateType::None
         }
     }
 
+    /// Rebuilds the template's wildcards and statistics from `members`, the
+    /// distinct messages it was assigned with their counts: wildcards where every
+    /// member has the same token become that token again. Returns whether the
+    /// template changed.
+    pub(crate) fn refine(
+        &mut self,
+        members: &[(Vec<String>, usize)],
+        interner: &mut TokenInterner,
+        value_stats: Option<&ValueStatsConfig>,
+    ) -> bool {
+        self.size = members.iter().map(|(_, count)| count).sum();
+        self.position_values.clear();
+        self.type_stats.clear();
+        self.value_stats.clear();
+
+        let mut refined = false;
+        for i in 0..self.token_ids.len() {
+            if !interner.is_wildcard(self.token_ids[i]) {
+                continue;
+            }
+            if let Some(((first, _), rest)) = members.split_first()
+                && rest.iter().all(|(tokens, _)| tokens[i] == first[i])
+            {
+                self.tokens[i] = first[i].clone();
+                self.token_ids[i] = interner.intern(&first[i]);
+                refined = true;
+                continue;
+            }
+            for (tokens, count) in members {
+                if interner
+                    .get(&tokens[i])
+                    .is_none_or(|id| !interner.is_param(id))
+                {
+                    self.observe_value(i, &tokens[i], *count, value_stats);
+                }
+            }
+        }
+        refined
+    }
+
+    /// Records the similarity of a matched message. Once enough are seen, the
+    /// cluster's threshold becomes their mean less two standard deviations, kept
+    /// within `ADAPTIVE_SIM_TH_RANGE` of `base_sim_th`.
+    pub(crate) fn observe_similarity(&mut self, similarity: f64, base_sim_th: f64) {
+        self.similarity_stats.add(similarity);
+        if self.similarity_stats.count < ADAPTIVE_SIM_TH_MIN_SAMPLES {
+            return;
+        }
+
+        let derived = self.similarity_stats.mean - 2.0 * self.similarity_stats.std_dev();
+        self.sim_th = Some(
+            derived
+                .clamp(
+                    base_sim_th - ADAPTIVE_SIM_TH_RANGE,
+                    base_sim_th + ADAPTIVE_SIM_TH_RANGE,
+                )
+                .clamp(0.0, 1.0),
+        );
+    }
+
+    /// Rewrites the template along an alignment with a message of another length:
+    /// substituted tokens become parameters and each run of unaligned tokens
+    /// collapses into one variable-length wildcard.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn merge_alignment<F>(
+        &mut self,
+        message_tokens: &[String],
+        ops: &[AlignOp],
+        variable_token: &str,
+        interner: &mut TokenInterner,
+        value_stats: Option<&ValueStatsConfig>,
+        mut get_next_token: F,
+    ) -> UpdateType
+    where
+        F: FnMut() -> String,
+    {
+        self.size += 1;
+
+        let variable_id = interner.intern(variable_token);
+        let mut tokens: Vec<String> = Vec::with_capacity(ops.len());
+        let mut token_ids: Vec<TokenId> = Vec::with_capacity(ops.len());
+        // Statistics follow their wildcards to where they are in the new template.
+        let mut type_stats = std::mem::take(&mut self.type_stats);
+        let mut old_value_stats = std::mem::take(&mut self.value_stats);
+        let mut observed: Vec<(usize, String, usize)> = Vec::new();
+        let (mut i, mut j) = (0, 0);
+
+        for op in ops {
+            match op {
+                AlignOp::Match | AlignOp::Substitute if interner.is_wildcard(self.token_ids[i]) => {
+                    if let Some(stats) = type_stats.remove(&i) {
+                        self.type_stats.insert(tokens.len(), stats);
+                    }
+                    if let Some(stats) = old_value_stats.remove(&i) {
+                        self.value_stats.insert(tokens.len(), stats);
+                    }
+                    if let Some(value) = message_tokens.get(j)
+                        && !interner.get(value).is_some_and(|id| interner.is_param(id))
+                    {
+                        observed.push((tokens.len(), value.clone(), 1));
+                    }
+                    tokens.push(self.tokens[i].clone());
+                    token_ids.push(self.token_ids[i]);
+                }
+                AlignOp::Match => {
+                    tokens.push(self.tokens[i].clone());
+                    token_ids.push(self.token_ids[i]);
+                }
+                AlignOp::Substitute => {
+                    observed.push((tokens.len(), self.tokens[i].clone(), self.size - 1));
+                    if let Some(value) = message_tokens.get(j) {
+                        observed.push((tokens.len(), value.clone(), 1));
+                    }
+                    let token = get_next_token();
+                    token_ids.push(interner.intern(&token));
+                    tokens.push(token);
+                }
+                AlignOp::Absorb | AlignOp::Skip | AlignOp::Insert | AlignOp::Delete => {
+                    if token_ids.last() != Some(&variable_id) {
+                        tokens.push(variable_token.to_string());
+                        token_ids.push(variable_id);
+                    }
+                }
+            }
+            if matches!(
+                op,
+                AlignOp::Match | AlignOp::Substitute | AlignOp::Skip | AlignOp::Delete
+            ) {
+                i += 1;
+            }
+            if matches!(
+                op,
+                AlignOp::Match | AlignOp::Substitute | AlignOp::Absorb | AlignOp::Insert
+            ) {
+                j += 1;
+            }
+        }
+
+        for (position, value, count) in observed {
+            self.observe_value(position, &value, count, value_stats);
+        }
+        if token_ids == self.token_ids {
+            return UpdateType::None;
+        }
+        self.tokens = tokens;
+        self.token_ids = token_ids;
+        UpdateType::Updated
+    }
+
+    /// Finds a wildcard position that only ever took a few constant values, each
+    /// seen at least `min_value_count` times, returning its values by frequency.
+    pub(crate) fn find_split(
+        &self,
+        max_values: usize,
+        min_value_count: usize,
+    ) -> Option<(usize, Vec<(String, usize)>)> {
+        let (&position, values) = self.position_values.iter().find(|(_, values)| {
+            !values.overflowed
+                && (2..=max_values).contains(&values.counts.len())
+                && values
+                    .counts
+                    .values()
+                    .all(|&count| count >= min_value_count)
+        })?;
+
+        let mut values: Vec<(String, usize)> = values
+            .counts
+            .iter()
+            .map(|(value, &count)| (value.clone(), count))
+            .collect();
+        values.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Some((position, values))
+    }
+
+    /// A copy of the cluster with `value` fixed at `position`, its statistics reset.
+    pub(crate) fn specialize(
+        &self,
+        cluster_id: usize,
+        position: usize,
+        value: &str,
+        size: usize,
+        interner: &mut TokenInterner,
+    ) -> Self {
+        let mut cluster = Self::with_token_ids(&self.tokens, &self.token_ids, cluster_id);
+        cluster.tokens[position] = value.to_string();
+        cluster.token_ids[position] = interner.intern(value);
+        cluster.size = size;
+        cluster.key_value_separator = self.key_value_separator.clone();
+        cluster.partition = self.partition.clone();
+        cluster.type_stats = self.type_stats.clone();
+        cluster.type_stats.remove(&position);
+        cluster.value_stats = self.value_stats.clone();
+        cluster.value_stats.remove(&position);
+        cluster.history = self.history.clone();
+        cluster
+    }
+
+    /// Folds `other` into this cluster, positions where the templates disagree
+    /// becoming parameters.
+    pub(crate) fn merge<F>(
+        &mut self,
+        other: &LogCluster,
+        interner: &mut TokenInterner,
+        value_stats: Option<&ValueStatsConfig>,
+        mut get_next_token: F,
+    ) where
+        F: FnMut() -> String,
+    {
+        for i in 0..self.token_ids.len().min(other.token_ids.len()) {
+            let (template_id, other_id) = (self.token_ids[i], other.token_ids[i]);
+            let (is_param, other_is_param) =
+                (interner.is_param(template_id), interner.is_param(other_id));
+
+            if other_is_param {
+                if let Some(other_stats) = other.type_stats.get(&i) {
+                    self.type_stats.entry(i).or_default().merge(other_stats);
+                }
+                if let Some(other_stats) = other.value_stats.get(&i) {
+                    match self.value_stats.get_mut(&i) {
+                        Some(stats) => stats.merge(other_stats),
+                        None => {
+                            self.value_stats.insert(i, other_stats.clone());
+                        }
+                    }
+                }
+            } else if other_id != template_id {
+                self.observe_value(i, &other.tokens[i], other.size, value_stats);
+            }
+            if !is_param && other_id != template_id {
+                let template_token = self.tokens[i].clone();
+                self.observe_value(i, &template_token, self.size, value_stats);
+            }
+
+            if template_id == other_id || interner.is_wildcard(template_id) {
+                continue;
+            }
+            if interner.is_wildcard(other_id) {
+                self.tokens[i] = other.tokens[i].clone();
+                self.token_ids[i] = other_id;
+                continue;
+            }
+
+            let token = get_next_token();
+            self.token_ids[i] = interner.intern(&token);
+            self.tokens[i] = token;
+        }
+
+        self.size += other.size;
+        self.position_values.clear();
+    }
+
     pub fn get_cluster_by_id(id: &usize) -> Option<Arc<Mutex<LogCluster>>> {
         CLUSTER_MAP.lock().unwrap().get(id).cloned()
     }
@@ -93,6 +607,29 @@ impl LogCluster {
         clusters.sort_by_key(|it| it.cluster_id);
         clusters
     }
+
+    /// Makes a cluster created outside `Node::add_cluster` visible to
+    /// `get_cluster_by_id` and `get_clusters`.
+    pub(crate) fn register(cluster: &Arc<Mutex<LogCluster>>) {
+        let cluster_id = cluster.lock().unwrap().cluster_id;
+        CLUSTER_MAP
+            .lock()
+            .unwrap()
+            .insert(cluster_id, cluster.clone());
+    }
+
+    /// Hides a cluster dropped by its drain from `get_cluster_by_id` and
+    /// `get_clusters`, unless its id has since been taken by another drain's cluster.
+    pub(crate) fn unregister(cluster: &Arc<Mutex<LogCluster>>) {
+        let cluster_id = cluster.lock().unwrap().cluster_id;
+        let mut cluster_map = CLUSTER_MAP.lock().unwrap();
+        if cluster_map
+            .get(&cluster_id)
+            .is_some_and(|c| Arc::ptr_eq(c, cluster))
+        {
+            cluster_map.remove(&cluster_id);
+        }
+    }
 }
 
 impl std::fmt::Display for LogCluster {
@@ -110,7 +647,7 @@ impl std::fmt::Display for LogCluster {
 #[derive(Debug)]
 pub struct Node {
     clusters: Vec<Arc<Mutex<LogCluster>>>,
-    children: HashMap<String, Box<Node>>,
+    children: HashMap<TokenId, Box<Node>>,
     wildcard_child: Option<Box<Node>>,
 }
 
@@ -129,23 +666,19 @@ impl Node {
         }
     }
 
-    pub fn get(&self, token: &String) -> Option<&Node> {
-        self.children.get(token).map(Box::as_ref)
-    }
-
-    pub fn find_next(&self, token: &String) -> Option<&Node> {
+    pub fn find_next(&self, token: TokenId) -> Option<&Node> {
         self.children
-            .get(token)
+            .get(&token)
             .map(Box::as_ref)
             .or_else(|| self.wildcard_child.as_ref().map(Box::as_ref))
     }
 
-    pub fn has_child(&self, token: &str) -> bool {
-        self.children.contains_key(token)
+    pub fn has_child(&self, token: TokenId) -> bool {
+        self.children.contains_key(&token)
     }
 
-    pub fn get_clusters(&self) -> Vec<Arc<Mutex<LogCluster>>> {
-        self.clusters.clone()
+    pub fn clusters(&self) -> &[Arc<Mutex<LogCluster>>] {
+        &self.clusters
     }
 
     pub fn child_count(&self) -> usize {
@@ -155,13 +688,13 @@ impl Node {
         1 + self.children.len()
     }
 
-    pub fn get_child_mut(&mut self, token: &str) -> Option<&mut Node> {
-        self.children.get_mut(token).map(|n| n.as_mut())
+    pub fn get_child_mut(&mut self, token: TokenId) -> Option<&mut Node> {
+        self.children.get_mut(&token).map(|n| n.as_mut())
     }
 
-    pub fn get_or_insert_child(&mut self, token: &str) -> &mut Node {
+    pub fn get_or_insert_child(&mut self, token: TokenId) -> &mut Node {
         self.children
-            .entry(token.to_owned())
+            .entry(token)
             .or_insert_with(|| Box::new(Node::new()))
             .as_mut()
     }
@@ -188,27 +721,52 @@ impl Node {
         result
     }
 
-    fn has_numbers(s: &str) -> bool {
-        s.chars().any(|c| c.is_ascii_digit())
-    }
-
-    pub fn add_cluster(
+    pub fn add_cluster<F>(
         &mut self,
         cluster_id: usize,
-        tokens: &Vec<String>,
+        tokens: &[String],
+        token_ids: &[TokenId],
         log_cluster_depth: usize,
         max_children: usize,
-        wildcardetrize_numeric_tokens: bool,
-    ) -> Option<Arc<Mutex<LogCluster>>> {
-        let cluster = Arc::new(Mutex::new(LogCluster::new(tokens, cluster_id)));
+        to_wildcard: F,
+    ) -> Option<Arc<Mutex<LogCluster>>>
+    where
+        F: Fn(&str, TokenId) -> bool,
+    {
+        let cluster = Arc::new(Mutex::new(LogCluster::with_token_ids(
+            tokens, token_ids, cluster_id,
+        )));
         CLUSTER_MAP
             .lock()
             .unwrap()
             .insert(cluster_id, cluster.clone());
 
+        self.insert_cluster(
+            cluster,
+            tokens,
+            token_ids,
+            log_cluster_depth,
+            max_children,
+            to_wildcard,
+        )
+    }
+
+    /// Routes `cluster` down the tree by its tokens, those `to_wildcard` holds for
+    /// going to the wildcard child.
+    pub(crate) fn insert_cluster<F>(
+        &mut self,
+        cluster: Arc<Mutex<LogCluster>>,
+        tokens: &[String],
+        token_ids: &[TokenId],
+        log_cluster_depth: usize,
+        max_children: usize,
+        to_wildcard: F,
+    ) -> Option<Arc<Mutex<LogCluster>>>
+    where
+        F: Fn(&str, TokenId) -> bool,
+    {
         let token_count = tokens.len();
         let max_node_depth = log_cluster_depth - 2;
-        let mut current_depth = 1;
 
         if token_count == 0 {
             self.clusters.push(cluster.clone());
@@ -217,53 +775,82 @@ impl Node {
 
         let mut cur_node = self;
 
-        for token in tokens {
+        for (current_depth, (token, &token_id)) in (1..).zip(tokens.iter().zip(token_ids.iter())) {
             if current_depth >= max_node_depth || current_depth >= token_count {
                 cur_node.clusters.push(cluster.clone());
                 return cur_node.clusters.last().cloned();
             }
 
-            if cur_node.has_child(token) {
-                cur_node = cur_node.get_child_mut(token).unwrap();
+            if cur_node.has_child(token_id) {
+                cur_node = cur_node.get_child_mut(token_id).unwrap();
+            } else if to_wildcard(token, token_id) {
+                cur_node = cur_node.get_or_insert_wildcard();
             } else {
-                let has_numbers = wildcardetrize_numeric_tokens && Self::has_numbers(token);
-
-                if has_numbers {
-                    cur_node = cur_node.get_or_insert_wildcard();
-                    continue;
-                }
-
                 if cur_node.has_wildcard() {
                     if cur_node.child_count() < max_children {
-                        cur_node = cur_node.get_or_insert_child(token);
+                        cur_node = cur_node.get_or_insert_child(token_id);
                     } else {
                         cur_node = cur_node.get_wildcard_mut().unwrap();
                     }
                 } else if cur_node.child_count() + 1 < max_children {
-                    cur_node = cur_node.get_or_insert_child(token);
+                    cur_node = cur_node.get_or_insert_child(token_id);
                 } else {
                     cur_node = cur_node.get_or_insert_wildcard();
                 }
             }
-            current_depth += 1;
         }
 
         None
     }
 
-    pub fn get_first_cluster_id(&self) -> Option<usize> {
-        let cluster = self.clusters.first()?;
-        Some(cluster.lock().unwrap().cluster_id)
+    /// Removes `cluster` from this node or its descendants, pruning nodes left
+    /// empty, and returns whether it was found.
+    pub fn remove_cluster(&mut self, cluster: &Arc<Mutex<LogCluster>>) -> bool {
+        if let Some(i) = self.clusters.iter().position(|c| Arc::ptr_eq(c, cluster)) {
+            self.clusters.remove(i);
+            return true;
+        }
+
+        let mut removed = false;
+        self.children.retain(|_, child| {
+            if removed {
+                return true;
+            }
+            removed = child.remove_cluster(cluster);
+            !(removed && child.is_empty())
+        });
+        if !removed && let Some(child) = self.wildcard_child.as_mut() {
+            removed = child.remove_cluster(cluster);
+            if removed && child.is_empty() {
+                self.wildcard_child = None;
+            }
+        }
+        removed
     }
 
-    pub fn search(&self, tokens: &[String], log_cluster_depth: usize) -> Option<&Node> {
-        let token_count = tokens.len();
+    fn is_empty(&self) -> bool {
+        self.clusters.is_empty() && self.children.is_empty() && self.wildcard_child.is_none()
+    }
+
+    /// Appends the clusters of this node and all its descendants.
+    pub fn collect_clusters(&self, clusters: &mut Vec<Arc<Mutex<LogCluster>>>) {
+        clusters.extend(self.clusters.iter().cloned());
+        for child in self.children() {
+            child.collect_clusters(clusters);
+        }
+    }
+
+    pub fn get_first_cluster(&self) -> Option<Arc<Mutex<LogCluster>>> {
+        self.clusters.first().cloned()
+    }
+
+    pub fn search(&self, token_ids: &[TokenId], log_cluster_depth: usize) -> Option<&Node> {
+        let token_count = token_ids.len();
 
         let mut cur_node = self;
         let max_node_depth = log_cluster_depth - 2;
 
-        let mut cur_node_depth = 1;
-        for token in tokens {
+        for (cur_node_depth, &token_id) in (1..).zip(token_ids.iter()) {
             if cur_node_depth >= max_node_depth {
                 break;
             }
@@ -271,12 +858,11 @@ impl Node {
                 break;
             }
 
-            if let Some(node) = cur_node.find_next(token) {
+            if let Some(node) = cur_node.find_next(token_id) {
                 cur_node = node;
             } else {
                 return None;
             }
-            cur_node_depth += 1;
         }
 
         Some(cur_node)
@@ -288,6 +874,7 @@ impl Node {
         depth: usize,
         writer: &mut W,
         max_clusters: usize,
+        interner: &TokenInterner,
     ) -> io::Result<()> {
         let mut out_str = "\t".repeat(depth);
 
@@ -310,7 +897,13 @@ impl Node {
         writeln!(writer, "{}", out_str)?;
 
         for (child_token, child_node) in &self.children {
-            child_node.print(child_token, depth + 1, writer, max_clusters)?;
+            child_node.print(
+                interner.resolve(*child_token),
+                depth + 1,
+                writer,
+                max_clusters,
+                interner,
+            )?;
         }
 
         for c in self.clusters.iter().take(max_clusters) {
@@ -320,17 +913,50 @@ impl Node {
 
         Ok(())
     }
+
+    /// Rebuilds a node, re-interning its child tokens and cluster templates.
+    pub fn from_serializable(s: SerializableNode, interner: &mut TokenInterner) -> Self {
+        let mut clusters: Vec<Arc<Mutex<LogCluster>>> = Vec::new();
+        for mut ser_cluster in s.clusters {
+            let cluster_id = ser_cluster.cluster_id;
+            ser_cluster.token_ids = interner.intern_all(&ser_cluster.tokens);
+            let cluster = Arc::new(Mutex::new(ser_cluster));
+            CLUSTER_MAP
+                .lock()
+                .unwrap()
+                .insert(cluster_id, cluster.clone());
+            clusters.push(cluster.clone());
+        }
+        Self {
+            clusters: clusters.clone(),
+
+            children: s
+                .children
+                .into_iter()
+                .map(|(k, v)| {
+                    (
+                        interner.intern(&k),
+                        Box::new(Node::from_serializable(v, interner)),
+                    )
+                })
+                .collect(),
+
+            wildcard_child: s
+                .wildcard_child
+                .map(|c| Box::new(Node::from_serializable(*c, interner))),
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct SerializableNode {
     clusters: Vec<LogCluster>,
     children: HashMap<String, SerializableNode>,
     wildcard_child: Option<Box<SerializableNode>>,
 }
 
-impl From<&Node> for SerializableNode {
-    fn from(node: &Node) -> Self {
+impl SerializableNode {
+    pub fn from_node(node: &Node, interner: &TokenInterner) -> Self {
         Self {
             clusters: node
                 .clusters
@@ -341,39 +967,40 @@ impl From<&Node> for SerializableNode {
             children: node
                 .children
                 .iter()
-                .map(|(k, v)| (k.clone(), SerializableNode::from(v.as_ref())))
+                .map(|(k, v)| {
+                    (
+                        interner.resolve(*k).to_string(),
+                        SerializableNode::from_node(v.as_ref(), interner),
+                    )
+                })
                 .collect(),
 
             wildcard_child: node
                 .wildcard_child
                 .as_ref()
-                .map(|c| Box::new(SerializableNode::from(c.as_ref()))),
+                .map(|c| Box::new(SerializableNode::from_node(c.as_ref(), interner))),
         }
     }
-}
 
-impl From<SerializableNode> for Node {
-    fn from(s: SerializableNode) -> Self {
-        let mut clusters: Vec<Arc<Mutex<LogCluster>>> = Vec::new();
-        for ser_cluster in s.clusters {
-            let cluster_id = ser_cluster.cluster_id;
-            let cluster = Arc::new(Mutex::new(ser_cluster));
-            CLUSTER_MAP
-                .lock()
-                .unwrap()
-                .insert(cluster_id, cluster.clone());
-            clusters.push(cluster.clone());
-        }
+    /// Builds the root of a snapshot, whose children are keyed by token count.
+    pub fn from_length_nodes(nodes: &HashMap<usize, Node>, interner: &TokenInterner) -> Self {
         Self {
-            clusters: clusters.clone(),
-
-            children: s
-                .children
-                .into_iter()
-                .map(|(k, v)| (k, Box::new(Node::from(v))))
+            clusters: Vec::new(),
+            children: nodes
+                .iter()
+                .map(|(k, v)| (k.to_string(), SerializableNode::from_node(v, interner)))
                 .collect(),
-
-            wildcard_child: s.wildcard_child.map(|c| Box::new(Node::from(*c))),
+            wildcard_child: None,
         }
     }
+
+    pub fn into_length_nodes(self, interner: &mut TokenInterner) -> HashMap<usize, Node> {
+        self.children
+            .into_iter()
+            .filter_map(|(k, v)| {
+                let token_count = k.parse::<usize>().ok()?;
+                Some((token_count, Node::from_serializable(v, interner)))
+            })
+            .collect()
+    }
 }
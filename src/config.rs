@@ -1,6 +1,12 @@
 use serde::{Deserialize, Serialize};
 
-use crate::masking::MaskingInstructionConfig;
+use crate::drain::{ClusterMergeConfig, TemplateSplitConfig};
+use crate::json_input::JsonInputConfig;
+use crate::masking::{DEFAULT_VAULT_CAPACITY, MaskingInstructionConfig};
+use crate::record_assembler::MultilineConfig;
+use crate::routing::RoutingPredicateConfig;
+use crate::tokenizer::TokenizerConfig;
+use crate::value_stats::ValueStatsConfig;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TemplateMinerConfig {
@@ -10,21 +16,66 @@ pub struct TemplateMinerConfig {
     pub drain_depth: usize,
     #[serde(default = "default_drain_sim_th")]
     pub drain_sim_th: f64,
+    /// See `DrainConfig::sim_th_by_length`.
+    #[serde(default)]
+    pub drain_sim_th_by_length: Vec<(usize, f64)>,
+    /// See `DrainConfig::adaptive_sim_th`.
+    #[serde(default)]
+    pub drain_adaptive_sim_th: bool,
     #[serde(default = "default_drain_max_children")]
     pub drain_max_children: usize,
     pub drain_max_clusters: Option<usize>,
     #[serde(default)]
     pub drain_extra_delimiters: Vec<String>,
+    /// Enables variable-length templates, see `DrainConfig::max_length_difference`.
+    #[serde(default)]
+    pub drain_max_length_difference: usize,
+    /// Splits over-generalized templates, see `TemplateSplitConfig`.
+    #[serde(default)]
+    pub drain_template_split: Option<TemplateSplitConfig>,
+    /// Merges near-identical clusters across tree branches, see `ClusterMergeConfig`.
+    #[serde(default)]
+    pub drain_cluster_merge: Option<ClusterMergeConfig>,
+    /// Keeps statistics of the values of each wildcard, see `ValueStatsConfig`.
+    #[serde(default)]
+    pub drain_value_stats: Option<ValueStatsConfig>,
+    /// Keeps a history of template versions, see `DrainConfig::template_history`.
+    #[serde(default)]
+    pub drain_template_history: usize,
+    #[serde(default)]
+    pub tokenizer: TokenizerConfig,
+    /// LogPAI-style header format, e.g. `<Date> <Time> <Level> <Component>: <Content>`.
+    #[serde(default)]
+    pub log_format: Option<String>,
+    /// Groups multi-line records such as stack traces before mining.
+    #[serde(default)]
+    pub multiline: Option<MultilineConfig>,
+    #[serde(default)]
+    pub json_input: JsonInputConfig,
     #[serde(default = "default_mask_prefix")]
     pub mask_prefix: String,
     #[serde(default = "default_mask_suffix")]
     pub mask_suffix: String,
     #[serde(default = "default_parametrize_numeric_tokens")]
     pub parametrize_numeric_tokens: bool,
+    /// See `DrainConfig::routing_predicates`.
+    #[serde(default)]
+    pub drain_routing_predicates: Vec<RoutingPredicateConfig>,
     #[serde(default = "default_parameter_extraction_cache_capacity")]
     pub parameter_extraction_cache_capacity: usize,
     #[serde(default)]
     pub masking_instructions: Vec<MaskingInstructionConfig>,
+    #[serde(default)]
+    pub grok_pattern_files: Vec<String>,
+    /// HMAC key of pseudonymizing masks; never serialized, so that saved or dumped
+    /// configs don't leak it.
+    #[serde(default, skip_serializing)]
+    pub pseudonymization_key: String,
+    #[serde(default)]
+    pub pseudonymization_vault: bool,
+    /// Pseudonyms the vault keeps, the least recently used evicted first.
+    #[serde(default = "default_pseudonymization_vault_capacity")]
+    pub pseudonymization_vault_capacity: usize,
     #[serde(default = "default_snapshot_interval_minutes")]
     pub snapshot_interval_minutes: u64,
     #[serde(default = "default_token_template")]
@@ -63,6 +114,10 @@ fn default_parametrize_numeric_tokens() -> bool {
     true
 }
 
+fn default_pseudonymization_vault_capacity() -> usize {
+    DEFAULT_VAULT_CAPACITY
+}
+
 fn default_parameter_extraction_cache_capacity() -> usize {
     3000
 }
@@ -77,15 +132,31 @@ impl Default for TemplateMinerConfig {
             engine: default_engine(),
             drain_depth: default_drain_depth(),
             drain_sim_th: default_drain_sim_th(),
+            drain_sim_th_by_length: vec![],
+            drain_adaptive_sim_th: false,
             drain_max_children: default_drain_max_children(),
             drain_max_clusters: None,
             drain_extra_delimiters: vec![],
+            drain_max_length_difference: 0,
+            drain_template_split: None,
+            drain_cluster_merge: None,
+            drain_value_stats: None,
+            drain_template_history: 0,
+            tokenizer: TokenizerConfig::default(),
+            log_format: None,
+            multiline: None,
+            json_input: JsonInputConfig::default(),
             mask_prefix: default_mask_prefix(),
             mask_suffix: default_mask_suffix(),
             token_template: default_token_template(),
             parametrize_numeric_tokens: default_parametrize_numeric_tokens(),
+            drain_routing_predicates: vec![],
             parameter_extraction_cache_capacity: default_parameter_extraction_cache_capacity(),
             masking_instructions: vec![],
+            grok_pattern_files: vec![],
+            pseudonymization_key: String::new(),
+            pseudonymization_vault: false,
+            pseudonymization_vault_capacity: default_pseudonymization_vault_capacity(),
             snapshot_interval_minutes: default_snapshot_interval_minutes(),
         }
     }
@@ -1,20 +1,124 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::io::{self, Write};
 use std::sync::{Arc, Mutex};
 
-use crate::cluster::{self, SerializableNode};
+use crate::alignment::{self, AlignBuffers, Alignment};
+use crate::cluster::SerializableNode;
 use crate::cluster::{LogCluster, Node, SearchStrategy, UpdateType};
+use crate::hierarchy::TemplateHierarchy;
+use crate::interner::{TokenId, TokenInterner, UNKNOWN_TOKEN};
+use crate::routing::{RoutingPredicateConfig, RoutingPredicates};
+use crate::tokenizer::{Tokenizer, WhitespaceTokenizer};
+use crate::value_stats::ValueStatsConfig;
 
 use profiling::function;
 
+/// Mask name of the variable-length wildcard, standing for zero or more tokens.
+pub const VARIABLE_LENGTH_MASK: &str = "...";
+
+/// Literal template tokens a message must match to be aligned with a template of
+/// another length, so that merges never leave a template without literal tokens.
+pub const VARIABLE_LENGTH_MIN_LITERALS: usize = 2;
+
+/// How far an adaptive cluster threshold may move away from the configured one.
+pub const ADAPTIVE_SIM_TH_RANGE: f64 = 0.2;
+
+/// Splitting of wildcard positions that turn out to hold only a few constants.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateSplitConfig {
+    /// Most distinct values a position may take to be split on.
+    #[serde(default = "default_split_max_values")]
+    pub max_values: usize,
+    /// Times each value must be seen before the position is split on.
+    #[serde(default = "default_split_min_value_count")]
+    pub min_value_count: usize,
+}
+
+fn default_split_max_values() -> usize {
+    4
+}
+
+fn default_split_min_value_count() -> usize {
+    10
+}
+
+impl Default for TemplateSplitConfig {
+    fn default() -> Self {
+        Self {
+            max_values: default_split_max_values(),
+            min_value_count: default_split_min_value_count(),
+        }
+    }
+}
+
+/// A cluster split on the values of one of its wildcard positions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitEvent {
+    pub cluster_id: usize,
+    pub position: usize,
+    /// Each value, by frequency, and the cluster that now has it at `position`: the
+    /// split cluster itself for the one it kept, else the cluster an earlier split
+    /// made for it or a new one.
+    pub values: Vec<(String, usize)>,
+}
+
+/// Merging of near-identical clusters that routing placed in different branches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterMergeConfig {
+    /// Template similarity at or above which two clusters are merged.
+    #[serde(default = "default_merge_sim_th")]
+    pub sim_th: f64,
+    /// Messages between merge passes; 0 only merges on `Drain::merge_clusters`.
+    #[serde(default)]
+    pub interval: usize,
+}
+
+fn default_merge_sim_th() -> f64 {
+    0.9
+}
+
+impl Default for ClusterMergeConfig {
+    fn default() -> Self {
+        Self {
+            sim_th: default_merge_sim_th(),
+            interval: 0,
+        }
+    }
+}
+
+/// A cluster folded into another by a merge pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeEvent {
+    pub cluster_id: usize,
+    pub merged_cluster_id: usize,
+}
+
 #[derive(Debug)]
 pub struct DrainConfig {
     pub log_cluster_depth: usize,
     pub sim_th: f64,
+    /// `(min_token_count, sim_th)` steps overriding `sim_th` for messages of at least
+    /// that many tokens, the step with the largest count applying.
+    pub sim_th_by_length: Vec<(usize, f64)>,
+    /// Lets each cluster derive its own threshold from the similarity of the
+    /// messages it absorbed, within `ADAPTIVE_SIM_TH_RANGE` of the configured one.
+    pub adaptive_sim_th: bool,
     pub max_children: usize,
     pub max_clusters: Option<usize>,
     pub extra_delimiters: Vec<String>,
     pub parametrize_numeric_tokens: bool,
+    /// Tokens matching any of these, or having numbers with
+    /// `parametrize_numeric_tokens`, go to wildcard children of the prefix tree.
+    pub routing_predicates: Vec<RoutingPredicateConfig>,
+    /// Messages finding no cluster of their own length are aligned with clusters at
+    /// most this many tokens longer or shorter; 0 disables variable-length templates.
+    pub max_length_difference: usize,
+    pub template_split: Option<TemplateSplitConfig>,
+    pub cluster_merge: Option<ClusterMergeConfig>,
+    pub value_stats: Option<ValueStatsConfig>,
+    /// Template versions kept per cluster; 0 keeps no history.
+    pub template_history: usize,
 
     pub token_prefix: String,
     pub token_suffix: String,
@@ -22,13 +126,32 @@ pub struct DrainConfig {
 }
 #[derive(Debug)]
 pub struct Drain {
-    root_node: Node,
+    // At first level, nodes are grouped by token count
+    root_node: HashMap<usize, Node>,
+    // Roots of messages mined with a partition key, kept apart from each other
+    partitions: HashMap<String, HashMap<usize, Node>>,
+    interner: TokenInterner,
     log_cluster_depth: usize,
     sim_th: f64,
+    sim_th_by_length: Vec<(usize, f64)>,
+    adaptive_sim_th: bool,
     max_children: usize,
     max_clusters: Option<usize>,
     extra_delimiters: Vec<String>,
     parametrize_numeric_tokens: bool,
+    routing_predicates: Vec<RoutingPredicateConfig>,
+    routing: RoutingPredicates,
+    max_length_difference: usize,
+    template_split: Option<TemplateSplitConfig>,
+    split_events: Vec<SplitEvent>,
+    cluster_merge: Option<ClusterMergeConfig>,
+    merge_events: Vec<MergeEvent>,
+    messages_since_merge: usize,
+    value_stats: Option<ValueStatsConfig>,
+    template_history: usize,
+    tokenizer: Arc<dyn Tokenizer>,
+    // Clusters whose template has a variable-length wildcard
+    variable_clusters: Vec<Arc<Mutex<LogCluster>>>,
 
     clusters_counter: usize,
 
@@ -50,62 +173,219 @@ impl Drain {
             cfg.token_template.as_str()
         };
 
+        let routing =
+            match RoutingPredicates::new(&cfg.routing_predicates, cfg.parametrize_numeric_tokens) {
+                Ok(x) => x,
+                Err(e) => {
+                    panic!("failed to create routing predicates, {}", e);
+                }
+            };
+
         Self {
-            root_node: Node::new(),
+            root_node: HashMap::new(),
+            partitions: HashMap::new(),
+            interner: TokenInterner::new(&cfg.token_prefix, &cfg.token_suffix, token_template),
             clusters_counter: 0,
             token_template_counter: 0,
 
             log_cluster_depth: cfg.log_cluster_depth,
             sim_th: cfg.sim_th,
+            sim_th_by_length: cfg.sim_th_by_length.clone(),
+            adaptive_sim_th: cfg.adaptive_sim_th,
             max_children: cfg.max_children,
             max_clusters: cfg.max_clusters,
             extra_delimiters: cfg.extra_delimiters.clone(),
             parametrize_numeric_tokens: cfg.parametrize_numeric_tokens,
+            routing_predicates: cfg.routing_predicates.clone(),
+            routing,
+            max_length_difference: cfg.max_length_difference,
+            template_split: cfg.template_split.clone(),
+            split_events: Vec::new(),
+            cluster_merge: cfg.cluster_merge.clone(),
+            merge_events: Vec::new(),
+            messages_since_merge: 0,
+            value_stats: cfg.value_stats.clone(),
+            template_history: cfg.template_history,
+            tokenizer: Arc::new(WhitespaceTokenizer::new(&cfg.extra_delimiters)),
+            variable_clusters: Vec::new(),
             token_template: token_template.to_string(),
             token_prefix: cfg.token_prefix.to_string(),
             token_suffix: cfg.token_suffix.to_string(),
         }
     }
 
+    pub fn tokenizer(&self) -> &Arc<dyn Tokenizer> {
+        &self.tokenizer
+    }
+
+    pub fn set_tokenizer(&mut self, tokenizer: Arc<dyn Tokenizer>) {
+        self.tokenizer = tokenizer;
+    }
+
+    /// Name of the parameter tokens the drain generates, numbered after it.
+    pub fn token_template(&self) -> &str {
+        &self.token_template
+    }
+
     pub fn get_content_as_tokens(&self, content: &str) -> Vec<String> {
-        let mut content = content.trim().to_string();
-        for delimiter in &self.extra_delimiters {
-            content = content.replace(delimiter, " ");
+        self.tokenizer.tokenize(content)
+    }
+
+    fn partition_root(&self, partition: Option<&str>) -> Option<&HashMap<usize, Node>> {
+        match partition {
+            Some(partition) => self.partitions.get(partition),
+            None => Some(&self.root_node),
         }
-        content.split_whitespace().map(|s| s.to_string()).collect()
     }
 
-    #[function]
     pub fn add_log_message(
         &mut self,
         content: &str,
+    ) -> (Option<Arc<Mutex<LogCluster>>>, UpdateType) {
+        self.add_partitioned_log_message(content, None)
+    }
+
+    /// Mines a message among those of the same partition only, so templates never
+    /// span partitions.
+    #[function]
+    pub fn add_partitioned_log_message(
+        &mut self,
+        content: &str,
+        partition: Option<&str>,
+    ) -> (Option<Arc<Mutex<LogCluster>>>, UpdateType) {
+        let (cluster, update_type) = self.mine_message(content, partition);
+
+        let interval = self
+            .cluster_merge
+            .as_ref()
+            .map_or(0, |merge| merge.interval);
+        if interval == 0 {
+            return (cluster, update_type);
+        }
+        self.messages_since_merge += 1;
+        if self.messages_since_merge < interval {
+            return (cluster, update_type);
+        }
+        self.messages_since_merge = 0;
+
+        let events = self.merge_clusters();
+        let cluster = cluster.map(|mut cluster| {
+            // Follow the message's cluster into whichever cluster absorbed it.
+            loop {
+                let cluster_id = cluster.lock().unwrap().cluster_id;
+                let Some(event) = events.iter().find(|e| e.merged_cluster_id == cluster_id) else {
+                    return cluster;
+                };
+                let survivor = self
+                    .all_clusters()
+                    .into_iter()
+                    .find(|c| c.lock().unwrap().cluster_id == event.cluster_id);
+                match survivor {
+                    Some(survivor) => cluster = survivor,
+                    None => return cluster,
+                }
+            }
+        });
+        self.merge_events.extend(events);
+        (cluster, update_type)
+    }
+
+    fn mine_message(
+        &mut self,
+        content: &str,
+        partition: Option<&str>,
     ) -> (Option<Arc<Mutex<LogCluster>>>, UpdateType) {
         let content_tokens = self.get_content_as_tokens(content);
+        // Tokens are only interned once they become part of a new template; unseen
+        // tokens can't equal anything in the tree anyway.
+        let token_ids = self.interner.lookup_all(&content_tokens);
+
+        let sim_th = self.sim_th_for(token_ids.len());
+        // Adaptive clusters may accept less similar messages, checked below against
+        // the matched cluster's own threshold.
+        let search_sim_th = if self.adaptive_sim_th {
+            (sim_th - ADAPTIVE_SIM_TH_RANGE).max(0.0)
+        } else {
+            sim_th
+        };
 
-        let match_result = Self::tree_search(
-            &self.root_node,
-            &content_tokens,
-            self.sim_th,
-            true,
-            self.log_cluster_depth,
-            &self.token_prefix,
-            &self.token_suffix,
-        );
+        let match_result = self
+            .partition_root(partition)
+            .and_then(|root_node| {
+                Self::tree_search(
+                    root_node,
+                    &token_ids,
+                    search_sim_th,
+                    true,
+                    self.log_cluster_depth,
+                    &self.interner,
+                )
+            })
+            .filter(|cluster| {
+                if !self.adaptive_sim_th {
+                    return true;
+                }
+                let mut cluster = cluster.lock().unwrap();
+                let (similarity, _) =
+                    Self::get_seq_distance(&cluster.token_ids, &token_ids, &self.interner, true);
+                if similarity < cluster.sim_th.unwrap_or(sim_th) {
+                    return false;
+                }
+                cluster.observe_similarity(similarity, sim_th);
+                true
+            });
+
+        if match_result.is_none()
+            && self.max_length_difference > 0
+            && let Some((cluster, alignment)) =
+                self.variable_length_search(&token_ids, partition, sim_th, false)
+        {
+            let variable_token = self.variable_token();
+            let mut counter = self.token_template_counter;
+
+            let mut template = cluster.lock().unwrap();
+            let update_type = template.merge_alignment(
+                &content_tokens,
+                &alignment.ops,
+                &variable_token,
+                &mut self.interner,
+                self.value_stats.as_ref(),
+                || {
+                    counter += 1;
+                    format!(
+                        "{}{}{}{}",
+                        self.token_prefix, self.token_template, counter, self.token_suffix
+                    )
+                },
+            );
+            if update_type != UpdateType::None {
+                template.record_version(Some(content), self.template_history);
+            }
+            drop(template);
+
+            self.token_template_counter = counter;
+            if !self
+                .variable_clusters
+                .iter()
+                .any(|c| Arc::ptr_eq(c, &cluster))
+            {
+                self.variable_clusters.push(cluster.clone());
+            }
+
+            return (Some(cluster), update_type);
+        }
 
         match match_result {
-            Some(cluster_id) => {
+            Some(cluster) => {
                 let mut counter = self.token_template_counter;
-                let cluster_ref = cluster::LogCluster::get_cluster_by_id(&cluster_id);
-
-                if cluster_ref.is_none() {
-                    println!("failed to get cluster by id {}", cluster_id);
-                    return (None, UpdateType::None);
-                }
 
-                let cluster = cluster_ref.unwrap();
-                let update_type = cluster.lock().unwrap().update_template(
+                let mut template = cluster.lock().unwrap();
+                let update_type = template.update_template_ids(
                     &content_tokens,
-                    |t| -> bool { Self::is_token(&self.token_prefix, &self.token_suffix, t) },
+                    &token_ids,
+                    &mut self.interner,
+                    self.template_split.as_ref().map(|split| split.max_values),
+                    self.value_stats.as_ref(),
                     || {
                         counter += 1;
                         format!(
@@ -114,82 +394,628 @@ impl Drain {
                         )
                     },
                 );
+                if update_type != UpdateType::None {
+                    template.record_version(Some(content), self.template_history);
+                }
+                drop(template);
 
                 self.token_template_counter = counter;
 
+                if let Some(split_cluster) =
+                    self.split_cluster(&cluster, &content_tokens, content, partition)
+                {
+                    return (Some(split_cluster), UpdateType::Split);
+                }
+
                 (Some(cluster), update_type)
             }
             None => {
                 self.clusters_counter += 1;
                 let cluster_id = self.clusters_counter;
-
+                let token_ids = self.interner.intern_all(&content_tokens);
+                let root_node = match partition {
+                    Some(partition) => self.partitions.entry(partition.to_string()).or_default(),
+                    None => &mut self.root_node,
+                };
+
+                // Masked values are typed wildcards, routed like the tokens the
+                // routing predicates pick.
+                let interner = &self.interner;
+                let routing = &self.routing;
+                let tokenizer = self.tokenizer.as_ref();
                 let cluster_ref = Self::add_seq_to_prefix_tree(
-                    &mut self.root_node,
+                    root_node,
                     cluster_id,
                     &content_tokens,
+                    &token_ids,
                     self.log_cluster_depth,
                     self.max_children,
-                    self.parametrize_numeric_tokens,
+                    |token, token_id| {
+                        interner.is_param(token_id) || routing.matches(token, tokenizer)
+                    },
                 );
 
-                if cluster_ref.is_none() {
+                let Some(cluster) = cluster_ref else {
                     return (None, UpdateType::None);
+                };
+
+                {
+                    let mut cluster = cluster.lock().unwrap();
+                    if let Some(separator) = self.tokenizer.key_value_separator() {
+                        cluster.key_value_separator = Some(separator.to_string());
+                    }
+                    cluster.partition = partition.map(str::to_string);
+                    cluster.record_version(Some(content), self.template_history);
                 }
 
-                (cluster_ref, UpdateType::Created)
+                (Some(cluster), UpdateType::Created)
             }
         }
     }
 
-    fn tree_search(
-        root_node: &Node,
+    /// Splits the cluster if one of its wildcard positions only took a few constant
+    /// values, returning the cluster the message now belongs to. Values that already
+    /// have a cluster, left by an earlier split, go back to it; the split cluster
+    /// keeps the most frequent of the others, and is dropped if there is none.
+    fn split_cluster(
+        &mut self,
+        cluster: &Arc<Mutex<LogCluster>>,
+        tokens: &[String],
+        content: &str,
+        partition: Option<&str>,
+    ) -> Option<Arc<Mutex<LogCluster>>> {
+        let split = self.template_split.as_ref()?;
+        let variable_id = self.interner.get(&self.variable_token());
+
+        let mut original = cluster.lock().unwrap();
+        if variable_id.is_some_and(|id| original.token_ids.contains(&id)) {
+            return None;
+        }
+        let (position, values) = original.find_split(split.max_values, split.min_value_count)?;
+
+        let existing: Vec<Option<Arc<Mutex<LogCluster>>>> = values
+            .iter()
+            .map(|(value, _)| {
+                let mut token_ids = original.token_ids.clone();
+                token_ids[position] = self.interner.get(value)?;
+                self.find_cluster(cluster, &token_ids, partition)
+            })
+            .collect();
+        let kept = existing.iter().position(Option::is_none);
+
+        let mut event = SplitEvent {
+            cluster_id: original.cluster_id,
+            position,
+            values: Vec::new(),
+        };
+        let mut message_cluster = cluster.clone();
+        let mut created = Vec::new();
+
+        for (i, ((value, count), existing)) in values.iter().zip(existing).enumerate() {
+            let (target, target_id) = if kept == Some(i) {
+                (cluster.clone(), original.cluster_id)
+            } else if let Some(existing) = existing {
+                let target_id = {
+                    let mut existing = existing.lock().unwrap();
+                    existing.size += count;
+                    existing.cluster_id
+                };
+                (existing, target_id)
+            } else {
+                self.clusters_counter += 1;
+                let mut specialized = original.specialize(
+                    self.clusters_counter,
+                    position,
+                    value,
+                    *count,
+                    &mut self.interner,
+                );
+                specialized.record_version(Some(content), self.template_history);
+                let specialized = Arc::new(Mutex::new(specialized));
+                created.push(specialized.clone());
+                (specialized, self.clusters_counter)
+            };
+            if tokens.get(position) == Some(value) {
+                message_cluster = target;
+            }
+            event.values.push((value.clone(), target_id));
+        }
+
+        let kept_tokens = kept.map(|i| {
+            let (value, count) = &values[i];
+            original.tokens[position] = value.clone();
+            original.token_ids[position] = self.interner.intern(value);
+            original.size = *count;
+            original.position_values.clear();
+            original.type_stats.remove(&position);
+            original.value_stats.remove(&position);
+            original.record_version(Some(content), self.template_history);
+            (original.tokens.clone(), original.token_ids.clone())
+        });
+        drop(original);
+
+        for specialized in &created {
+            let (specialized_tokens, specialized_ids) = {
+                let specialized = specialized.lock().unwrap();
+                (specialized.tokens.clone(), specialized.token_ids.clone())
+            };
+            self.rehome_cluster(
+                specialized,
+                &specialized_tokens,
+                &specialized_ids,
+                partition,
+            );
+            LogCluster::register(specialized);
+        }
+        match kept_tokens {
+            Some((original_tokens, original_ids)) => {
+                self.rehome_cluster(cluster, &original_tokens, &original_ids, partition);
+            }
+            None => {
+                self.remove_cluster(cluster, partition);
+            }
+        }
+
+        self.split_events.push(event);
+        Some(message_cluster)
+    }
+
+    /// The cluster of the partition other than `cluster` whose template is `token_ids`.
+    fn find_cluster(
+        &self,
+        cluster: &Arc<Mutex<LogCluster>>,
+        token_ids: &[TokenId],
+        partition: Option<&str>,
+    ) -> Option<Arc<Mutex<LogCluster>>> {
+        let mut clusters = Vec::new();
+        self.partition_root(partition)?
+            .get(&token_ids.len())?
+            .collect_clusters(&mut clusters);
+        clusters
+            .into_iter()
+            .find(|c| !Arc::ptr_eq(c, cluster) && c.lock().unwrap().token_ids == token_ids)
+    }
+
+    /// Takes the clusters split since the last call.
+    pub fn take_split_events(&mut self) -> Vec<SplitEvent> {
+        std::mem::take(&mut self.split_events)
+    }
+
+    /// Merges clusters of the same length whose templates are at least
+    /// `ClusterMergeConfig::sim_th` alike, wherever the tree routed them, and
+    /// re-homes each merged cluster by its generalized template.
+    pub fn merge_clusters(&mut self) -> Vec<MergeEvent> {
+        let sim_th = self
+            .cluster_merge
+            .as_ref()
+            .map_or_else(default_merge_sim_th, |merge| merge.sim_th);
+        let variable_id = self.interner.get(&self.variable_token());
+
+        let mut partitions: Vec<Option<String>> = std::iter::once(None)
+            .chain(self.partitions.keys().cloned().map(Some))
+            .collect();
+        // Passes run in a fixed order so that parameter names don't depend on it.
+        partitions.sort();
+
+        let mut events = Vec::new();
+        for partition in partitions {
+            let Some(root_node) = self.partition_root(partition.as_deref()) else {
+                continue;
+            };
+            let mut token_counts: Vec<usize> = root_node.keys().copied().collect();
+            token_counts.sort_unstable();
+
+            for token_count in token_counts {
+                let mut clusters = Vec::new();
+                if let Some(node) = self
+                    .partition_root(partition.as_deref())
+                    .and_then(|root_node| root_node.get(&token_count))
+                {
+                    node.collect_clusters(&mut clusters);
+                }
+                // Variable-length templates are left to alignment.
+                clusters.retain(|cluster| {
+                    let cluster = cluster.lock().unwrap();
+                    cluster.token_ids.len() == token_count
+                        && !variable_id.is_some_and(|id| cluster.token_ids.contains(&id))
+                });
+                clusters.sort_by_key(|cluster| cluster.lock().unwrap().cluster_id);
+
+                let mut i = 0;
+                while i < clusters.len() {
+                    let mut j = i + 1;
+                    while j < clusters.len() {
+                        let sim = Self::get_template_similarity(
+                            &clusters[i].lock().unwrap().token_ids,
+                            &clusters[j].lock().unwrap().token_ids,
+                            &self.interner,
+                        );
+                        if sim < sim_th {
+                            j += 1;
+                            continue;
+                        }
+
+                        let other = clusters.remove(j);
+                        let (survivor, merged) = {
+                            let (a, b) = (clusters[i].lock().unwrap(), other.lock().unwrap());
+                            if (b.size, std::cmp::Reverse(b.cluster_id))
+                                > (a.size, std::cmp::Reverse(a.cluster_id))
+                            {
+                                (other.clone(), clusters[i].clone())
+                            } else {
+                                (clusters[i].clone(), other.clone())
+                            }
+                        };
+                        events.push(self.merge_cluster_pair(
+                            &survivor,
+                            &merged,
+                            partition.as_deref(),
+                            token_count,
+                        ));
+                        clusters[i] = survivor;
+                        // The generalized template may now be alike earlier rejects.
+                        j = i + 1;
+                    }
+                    i += 1;
+                }
+            }
+        }
+        events
+    }
+
+    fn merge_cluster_pair(
+        &mut self,
+        survivor: &Arc<Mutex<LogCluster>>,
+        merged: &Arc<Mutex<LogCluster>>,
+        partition: Option<&str>,
+        token_count: usize,
+    ) -> MergeEvent {
+        let mut counter = self.token_template_counter;
+        let (tokens, token_ids, event) = {
+            let mut survivor = survivor.lock().unwrap();
+            let merged = merged.lock().unwrap();
+            let template = survivor.token_ids.clone();
+            survivor.merge(
+                &merged,
+                &mut self.interner,
+                self.value_stats.as_ref(),
+                || {
+                    counter += 1;
+                    format!(
+                        "{}{}{}{}",
+                        self.token_prefix, self.token_template, counter, self.token_suffix
+                    )
+                },
+            );
+            if survivor.token_ids != template {
+                survivor.record_version(None, self.template_history);
+            }
+            (
+                survivor.tokens.clone(),
+                survivor.token_ids.clone(),
+                MergeEvent {
+                    cluster_id: survivor.cluster_id,
+                    merged_cluster_id: merged.cluster_id,
+                },
+            )
+        };
+        self.token_template_counter = counter;
+        self.variable_clusters.retain(|c| !Arc::ptr_eq(c, merged));
+        LogCluster::unregister(merged);
+
+        let root_node = match partition {
+            Some(partition) => self.partitions.entry(partition.to_string()).or_default(),
+            None => &mut self.root_node,
+        };
+        root_node
+            .entry(token_count)
+            .or_default()
+            .remove_cluster(merged);
+        self.rehome_cluster(survivor, &tokens, &token_ids, partition);
+        event
+    }
+
+    /// Moves a cluster to where its current template routes in the prefix tree.
+    fn rehome_cluster(
+        &mut self,
+        cluster: &Arc<Mutex<LogCluster>>,
         tokens: &[String],
+        token_ids: &[TokenId],
+        partition: Option<&str>,
+    ) {
+        let root_node = match partition {
+            Some(partition) => self.partitions.entry(partition.to_string()).or_default(),
+            None => &mut self.root_node,
+        };
+        let length_node = root_node.entry(tokens.len()).or_default();
+        length_node.remove_cluster(cluster);
+
+        // Parameters route to wildcard children, where messages of any value look.
+        let interner = &self.interner;
+        let routing = &self.routing;
+        let tokenizer = self.tokenizer.as_ref();
+        length_node.insert_cluster(
+            cluster.clone(),
+            tokens,
+            token_ids,
+            self.log_cluster_depth,
+            self.max_children,
+            |token, token_id| interner.is_param(token_id) || routing.matches(token, tokenizer),
+        );
+    }
+
+    /// Resets the drain to a corpus: the clusters mined so far, in any partition,
+    /// are discarded along with the parameter and cluster counters, and `contents`
+    /// are mined so that the templates don't depend on their order. A first pass
+    /// clusters the messages in sorted order; a second reassigns each message to
+    /// the most specific template it fits, drops the clusters left without
+    /// messages and narrows each template to the messages it kept. Returns the
+    /// cluster of each message, in the order given.
+    pub fn rebuild_from_batch(&mut self, contents: &[&str]) -> Vec<Option<Arc<Mutex<LogCluster>>>> {
+        for cluster in self.all_clusters() {
+            LogCluster::unregister(&cluster);
+        }
+        self.root_node.clear();
+        self.partitions.clear();
+        self.variable_clusters.clear();
+        self.split_events.clear();
+        self.merge_events.clear();
+        self.messages_since_merge = 0;
+        self.clusters_counter = 0;
+        self.token_template_counter = 0;
+        self.interner =
+            TokenInterner::new(&self.token_prefix, &self.token_suffix, &self.token_template);
+
+        let mut sorted = contents.to_vec();
+        sorted.sort_unstable();
+        for content in &sorted {
+            self.add_log_message(content);
+        }
+
+        let variable_id = self.interner.get(&self.variable_token());
+        let mut clusters: Vec<Arc<Mutex<LogCluster>>> = self
+            .all_clusters()
+            .into_iter()
+            .filter(|cluster| cluster.lock().unwrap().partition.is_none())
+            .collect();
+        clusters.sort_by_key(|cluster| cluster.lock().unwrap().cluster_id);
+
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for content in &sorted {
+            *counts.entry(content).or_default() += 1;
+        }
+
+        let mut assignments = HashMap::new();
+        let mut members: HashMap<usize, Vec<(Vec<String>, usize)>> = HashMap::new();
+        for (&content, &count) in &counts {
+            let tokens = self.get_content_as_tokens(content);
+            let token_ids = self.interner.lookup_all(&tokens);
+
+            // The fitting template with the fewest parameters, the oldest on ties.
+            let best_fit = clusters
+                .iter()
+                .filter_map(|cluster| {
+                    let template = cluster.lock().unwrap();
+                    let fits = template.token_ids.len() == token_ids.len()
+                        && !variable_id.is_some_and(|id| template.token_ids.contains(&id))
+                        && template
+                            .token_ids
+                            .iter()
+                            .zip(&token_ids)
+                            .all(|(&t, &m)| self.interner.accepts(t, m));
+                    if !fits {
+                        return None;
+                    }
+                    let exact = template
+                        .token_ids
+                        .iter()
+                        .zip(&token_ids)
+                        .filter(|(t, m)| t == m)
+                        .count();
+                    Some((exact, std::cmp::Reverse(template.cluster_id), cluster))
+                })
+                .max_by_key(|(exact, id, _)| (*exact, *id))
+                .map(|(_, _, cluster)| cluster.clone());
+
+            // Variable-length templates are matched by alignment instead.
+            let assigned =
+                best_fit.or_else(|| self.match_cluster(content, SearchStrategy::Fallback));
+            if let Some(cluster) = &assigned {
+                let cluster_id = cluster.lock().unwrap().cluster_id;
+                members.entry(cluster_id).or_default().push((tokens, count));
+            }
+            assignments.insert(content, assigned);
+        }
+
+        for cluster in &clusters {
+            let cluster_id = cluster.lock().unwrap().cluster_id;
+            let Some(members) = members.get(&cluster_id) else {
+                self.remove_cluster(cluster, None);
+                continue;
+            };
+
+            let mut template = cluster.lock().unwrap();
+            if variable_id.is_some_and(|id| template.token_ids.contains(&id)) {
+                template.size = members.iter().map(|(_, count)| count).sum();
+                continue;
+            }
+            if template.refine(members, &mut self.interner, self.value_stats.as_ref()) {
+                template.record_version(None, self.template_history);
+                let (tokens, token_ids) = (template.tokens.clone(), template.token_ids.clone());
+                drop(template);
+                self.rehome_cluster(cluster, &tokens, &token_ids, None);
+            }
+        }
+
+        contents
+            .iter()
+            .map(|content| assignments[content].clone())
+            .collect()
+    }
+
+    /// Drops a cluster from the prefix tree of its partition and from the cluster map.
+    fn remove_cluster(&mut self, cluster: &Arc<Mutex<LogCluster>>, partition: Option<&str>) {
+        let root_node = match partition {
+            Some(partition) => self.partitions.get_mut(partition),
+            None => Some(&mut self.root_node),
+        };
+        for length_node in root_node.into_iter().flat_map(|r| r.values_mut()) {
+            if length_node.remove_cluster(cluster) {
+                break;
+            }
+        }
+        self.variable_clusters.retain(|c| !Arc::ptr_eq(c, cluster));
+        LogCluster::unregister(cluster);
+    }
+
+    /// Clusters merged by periodic merge passes not yet taken.
+    pub fn merge_events(&self) -> &[MergeEvent] {
+        &self.merge_events
+    }
+
+    /// Takes the clusters merged by periodic merge passes since the last call.
+    pub fn take_merge_events(&mut self) -> Vec<MergeEvent> {
+        std::mem::take(&mut self.merge_events)
+    }
+
+    /// Similarity threshold for messages of `token_count` tokens.
+    pub fn sim_th_for(&self, token_count: usize) -> f64 {
+        self.sim_th_by_length
+            .iter()
+            .filter(|(min_token_count, _)| *min_token_count <= token_count)
+            .max_by_key(|(min_token_count, _)| *min_token_count)
+            .map_or(self.sim_th, |(_, sim_th)| *sim_th)
+    }
+
+    fn variable_token(&self) -> String {
+        format!(
+            "{}{}{}",
+            self.token_prefix, VARIABLE_LENGTH_MASK, self.token_suffix
+        )
+    }
+
+    /// Finds the cluster of nearby length, or with a variable-length wildcard, that
+    /// aligns best with the message. With `exact`, the message must fit the template
+    /// as is.
+    fn variable_length_search(
+        &self,
+        token_ids: &[TokenId],
+        partition: Option<&str>,
+        sim_th: f64,
+        exact: bool,
+    ) -> Option<(Arc<Mutex<LogCluster>>, Alignment)> {
+        let root_node = self.partition_root(partition)?;
+        let token_count = token_ids.len();
+
+        let mut candidates: Vec<Arc<Mutex<LogCluster>>> = Vec::new();
+        let min_count = token_count.saturating_sub(self.max_length_difference);
+        for count in min_count..=token_count + self.max_length_difference {
+            if count != token_count
+                && let Some(node) = root_node.get(&count)
+            {
+                node.collect_clusters(&mut candidates);
+            }
+        }
+        for cluster in &self.variable_clusters {
+            if cluster.lock().unwrap().partition.as_deref() == partition
+                && !candidates.iter().any(|c| Arc::ptr_eq(c, cluster))
+            {
+                candidates.push(cluster.clone());
+            }
+        }
+
+        let variable_id = self
+            .interner
+            .get(&self.variable_token())
+            .unwrap_or(UNKNOWN_TOKEN);
+
+        let mut buffers = AlignBuffers::default();
+        let mut max_sim = -1.0;
+        let mut best: Option<(Arc<Mutex<LogCluster>>, Alignment)> = None;
+        for cluster in candidates {
+            let (sim, alignment) = {
+                let c = cluster.lock().unwrap();
+                let fixed_count = c.token_ids.iter().filter(|&&t| t != variable_id).count();
+                let has_variable = fixed_count < c.token_ids.len();
+
+                // Skip templates that can't fit or score enough whatever the alignment.
+                if exact {
+                    if fixed_count > token_count || !has_variable && fixed_count != token_count {
+                        continue;
+                    }
+                } else {
+                    let literal_count = c
+                        .token_ids
+                        .iter()
+                        .filter(|&&t| t != variable_id && !self.interner.is_param(t))
+                        .count()
+                        .min(token_count);
+                    let sim_bound = literal_count as f64 / fixed_count.max(token_count) as f64;
+                    if literal_count < VARIABLE_LENGTH_MIN_LITERALS || sim_bound < sim_th {
+                        continue;
+                    }
+                }
+
+                let alignment = alignment::align(
+                    &c.token_ids,
+                    token_ids,
+                    variable_id,
+                    &self.interner,
+                    &mut buffers,
+                );
+                (alignment.similarity(fixed_count, token_count), alignment)
+            };
+
+            if exact && !alignment.is_exact()
+                || !exact && (sim < sim_th || alignment.literal < VARIABLE_LENGTH_MIN_LITERALS)
+            {
+                continue;
+            }
+            if sim > max_sim {
+                max_sim = sim;
+                best = Some((cluster, alignment));
+            }
+        }
+        best
+    }
+
+    fn tree_search(
+        root_node: &HashMap<usize, Node>,
+        token_ids: &[TokenId],
         sim_th: f64,
         include_params: bool,
         log_cluster_depth: usize,
-        token_prefix: &String,
-        token_suffix: &String,
-    ) -> Option<usize> {
-        let token_count = tokens.len();
+        interner: &TokenInterner,
+    ) -> Option<Arc<Mutex<LogCluster>>> {
+        let token_count = token_ids.len();
 
-        // At first level, children are grouped by token count
-        let cur_node = root_node.get(&token_count.to_string())?;
+        let cur_node = root_node.get(&token_count)?;
 
         if token_count == 0 {
-            return cur_node.get_first_cluster_id();
+            return cur_node.get_first_cluster();
         }
 
-        let cur_node = cur_node.search(tokens, log_cluster_depth)?;
+        let cur_node = cur_node.search(token_ids, log_cluster_depth)?;
 
-        Self::fast_match(
-            cur_node,
-            tokens,
-            sim_th,
-            include_params,
-            token_prefix,
-            token_suffix,
-        )
+        Self::fast_match(cur_node, token_ids, sim_th, include_params, interner)
     }
 
     fn fast_match(
         node: &Node,
-        tokens: &[String],
+        token_ids: &[TokenId],
         sim_th: f64,
         include_params: bool,
-        token_prefix: &String,
-        token_suffix: &String,
-    ) -> Option<usize> {
+        interner: &TokenInterner,
+    ) -> Option<Arc<Mutex<LogCluster>>> {
         let mut max_sim = -1.0;
         let mut max_param_count = -1;
-        let mut max_cluster: Option<Arc<Mutex<LogCluster>>> = None;
+        let mut max_cluster: Option<&Arc<Mutex<LogCluster>>> = None;
 
-        let clusters = node.get_clusters();
-        for cluster in clusters {
+        for cluster in node.clusters() {
             let (cur_sim, param_count) = Self::get_seq_distance(
-                &cluster.lock().unwrap().get_tokens(),
-                tokens,
-                token_prefix,
-                token_suffix,
+                &cluster.lock().unwrap().token_ids,
+                token_ids,
+                interner,
                 include_params,
             );
             if cur_sim > max_sim || (cur_sim == max_sim && param_count > max_param_count) {
@@ -200,7 +1026,7 @@ impl Drain {
         }
 
         if max_sim >= sim_th {
-            max_cluster.map(|c| c.lock().unwrap().get_cluster_id())
+            max_cluster.cloned()
         } else {
             None
         }
@@ -208,33 +1034,19 @@ impl Drain {
 
     fn full_match(
         node: &Node,
-        tokens: &[String],
+        token_ids: &[TokenId],
         sim_th: f64,
         include_params: bool,
-        token_prefix: &String,
-        token_suffix: &String,
-    ) -> Option<usize> {
-        if let Some(id) = Self::fast_match(
-            node,
-            tokens,
-            sim_th,
-            include_params,
-            token_prefix,
-            token_suffix,
-        ) {
-            return Some(id);
+        interner: &TokenInterner,
+    ) -> Option<Arc<Mutex<LogCluster>>> {
+        if let Some(cluster) = Self::fast_match(node, token_ids, sim_th, include_params, interner) {
+            return Some(cluster);
         }
 
         for n in node.children() {
-            if let Some(id) = Self::full_match(
-                n,
-                tokens,
-                sim_th,
-                include_params,
-                token_prefix,
-                token_suffix,
-            ) {
-                return Some(id);
+            if let Some(cluster) = Self::full_match(n, token_ids, sim_th, include_params, interner)
+            {
+                return Some(cluster);
             }
         }
 
@@ -242,10 +1054,9 @@ impl Drain {
     }
 
     fn get_seq_distance(
-        seq1: &[String],
-        seq2: &[String],
-        token_prefix: &String,
-        token_suffix: &String,
+        seq1: &[TokenId],
+        seq2: &[TokenId],
+        interner: &TokenInterner,
         include_params: bool,
     ) -> (f64, i32) {
         if seq1.len() != seq2.len() {
@@ -259,9 +1070,12 @@ impl Drain {
         let mut sim_tokens = 0;
         let mut param_count = 0;
 
-        for (token1, token2) in seq1.iter().zip(seq2.iter()) {
-            if Self::is_token(token_prefix, token_suffix, token1) {
-                param_count += 1;
+        for (&token1, &token2) in seq1.iter().zip(seq2.iter()) {
+            // Typed parameters such as masked values only match their own kind.
+            if interner.is_param(token1) {
+                if interner.accepts(token1, token2) {
+                    param_count += 1;
+                }
                 continue;
             }
             if token1 == token2 {
@@ -277,31 +1091,55 @@ impl Drain {
         (ret_val, param_count)
     }
 
-    fn is_token(token_prefix: &String, token_suffix: &String, token: &str) -> bool {
-        token.starts_with(token_prefix) && token.ends_with(token_suffix)
+    /// Share of positions where two templates agree, a parameter in either agreeing
+    /// with anything.
+    fn get_template_similarity(
+        seq1: &[TokenId],
+        seq2: &[TokenId],
+        interner: &TokenInterner,
+    ) -> f64 {
+        if seq1.len() != seq2.len() {
+            return 0.0;
+        }
+        if seq1.is_empty() {
+            return 1.0;
+        }
+
+        let sim_tokens = seq1
+            .iter()
+            .zip(seq2.iter())
+            .filter(|&(&token1, &token2)| {
+                interner.accepts(token1, token2) || interner.accepts(token2, token1)
+            })
+            .count();
+        sim_tokens as f64 / seq1.len() as f64
     }
 
-    fn add_seq_to_prefix_tree(
-        root_node: &mut Node,
+    fn add_seq_to_prefix_tree<F>(
+        root_node: &mut HashMap<usize, Node>,
         cluster_id: usize,
-        tokens: &Vec<String>,
+        tokens: &[String],
+        token_ids: &[TokenId],
         log_cluster_depth: usize,
         max_children: usize,
-        parametrize_numeric_tokens: bool,
-    ) -> Option<Arc<Mutex<LogCluster>>> {
+        to_wildcard: F,
+    ) -> Option<Arc<Mutex<LogCluster>>>
+    where
+        F: Fn(&str, TokenId) -> bool,
+    {
         let token_count = tokens.len();
-        let token_count_str = token_count.to_string();
 
-        let first_layer_node = root_node.get_or_insert_child(&token_count_str);
+        let first_layer_node = root_node.entry(token_count).or_default();
 
         let cur_node = first_layer_node;
 
         cur_node.add_cluster(
             cluster_id,
             tokens,
+            token_ids,
             log_cluster_depth,
             max_children,
-            parametrize_numeric_tokens,
+            to_wildcard,
         )
     }
 
@@ -309,73 +1147,119 @@ impl Drain {
         &self,
         content: &str,
         strategy: SearchStrategy,
+    ) -> Option<Arc<Mutex<LogCluster>>> {
+        self.match_partitioned_cluster(content, None, strategy)
+    }
+
+    pub fn match_partitioned_cluster(
+        &self,
+        content: &str,
+        partition: Option<&str>,
+        strategy: SearchStrategy,
     ) -> Option<Arc<Mutex<LogCluster>>> {
         let required_sim_th = 1.0;
 
+        let root_node = self.partition_root(partition)?;
         let tokens = self.get_content_as_tokens(content);
+        let token_ids = self.interner.lookup_all(&tokens);
 
         let full_search = || {
-            let token_count = tokens.len();
-
-            // At first level, children are grouped by token count
-            let cur_node = self.root_node.get(&token_count.to_string())?;
+            let cur_node = root_node.get(&token_ids.len())?;
 
-            Self::full_match(
-                cur_node,
-                &tokens,
-                required_sim_th,
-                true,
-                &self.token_prefix,
-                &self.token_suffix,
-            )
-            .and_then(|id| cluster::LogCluster::get_cluster_by_id(&id))
+            Self::full_match(cur_node, &token_ids, required_sim_th, true, &self.interner)
         };
 
         match strategy {
             SearchStrategy::Full => full_search(),
 
             SearchStrategy::Fast => Self::tree_search(
-                &self.root_node,
-                &tokens,
+                root_node,
+                &token_ids,
                 required_sim_th,
                 true,
                 self.log_cluster_depth,
-                &self.token_prefix,
-                &self.token_suffix,
-            )
-            .and_then(|id| cluster::LogCluster::get_cluster_by_id(&id)),
+                &self.interner,
+            ),
 
             SearchStrategy::Fallback => Self::tree_search(
-                &self.root_node,
-                &tokens,
+                root_node,
+                &token_ids,
                 required_sim_th,
                 true,
                 self.log_cluster_depth,
-                &self.token_prefix,
-                &self.token_suffix,
+                &self.interner,
             )
-            .and_then(|id| cluster::LogCluster::get_cluster_by_id(&id))
             .or_else(full_search),
         }
+        .or_else(|| {
+            if self.max_length_difference == 0 {
+                return None;
+            }
+            self.variable_length_search(&token_ids, partition, required_sim_th, true)
+                .map(|(cluster, _)| cluster)
+        })
     }
 
     pub fn print_tree<W: Write>(&self, writer: &mut W, max_clusters: usize) -> io::Result<()> {
-        // self.print_node("root", &self.root_node, 0, writer, max_clusters)
-        self.root_node.print("root", 0, writer, max_clusters)
+        writeln!(writer, "<root>")?;
+        self.print_length_nodes(&self.root_node, writer, max_clusters)?;
+
+        let mut partitions: Vec<&String> = self.partitions.keys().collect();
+        partitions.sort();
+        for partition in partitions {
+            writeln!(writer, "<root partition={}>", partition)?;
+            self.print_length_nodes(&self.partitions[partition], writer, max_clusters)?;
+        }
+        Ok(())
+    }
+
+    fn print_length_nodes<W: Write>(
+        &self,
+        root_node: &HashMap<usize, Node>,
+        writer: &mut W,
+        max_clusters: usize,
+    ) -> io::Result<()> {
+        let mut token_counts: Vec<&usize> = root_node.keys().collect();
+        token_counts.sort();
+        for token_count in token_counts {
+            root_node[token_count].print(
+                &token_count.to_string(),
+                1,
+                writer,
+                max_clusters,
+                &self.interner,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Every cluster in the tree, at any depth and in any partition.
+    fn all_clusters(&self) -> Vec<Arc<Mutex<LogCluster>>> {
+        let mut clusters = Vec::new();
+        let roots = std::iter::once(&self.root_node).chain(self.partitions.values());
+        for n in roots.flat_map(|root_node| root_node.values()) {
+            n.collect_clusters(&mut clusters);
+        }
+        clusters
+    }
+
+    /// The templates of all clusters, each under the most specific template of
+    /// its partition generalizing it.
+    pub fn template_hierarchy(&self) -> TemplateHierarchy {
+        TemplateHierarchy::build(&self.all_clusters(), &self.interner)
+    }
+
+    pub fn partitions(&self) -> Vec<&str> {
+        self.partitions.keys().map(String::as_str).collect()
     }
 
     pub fn get_clusters(&self) -> Vec<LogCluster> {
         let mut clusters = Vec::new();
-        let mut append_clusters = |n: &Node| {
-            for c in n.get_clusters() {
+        let roots = std::iter::once(&self.root_node).chain(self.partitions.values());
+        for n in roots.flat_map(|root_node| root_node.values()) {
+            for c in n.clusters() {
                 clusters.push(c.lock().unwrap().clone());
             }
-        };
-
-        append_clusters(&self.root_node);
-
-        for n in self.root_node.children() {
-            append_clusters(n);
         }
 
         clusters
@@ -385,12 +1269,30 @@ impl Drain {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SerializableDrain {
     root_node: SerializableNode,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    partitions: HashMap<String, SerializableNode>,
     log_cluster_depth: usize,
     sim_th: f64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sim_th_by_length: Vec<(usize, f64)>,
+    #[serde(default)]
+    adaptive_sim_th: bool,
     max_children: usize,
     max_clusters: Option<usize>,
     extra_delimiters: Vec<String>,
     parametrize_numeric_tokens: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    routing_predicates: Vec<RoutingPredicateConfig>,
+    #[serde(default)]
+    max_length_difference: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    template_split: Option<TemplateSplitConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cluster_merge: Option<ClusterMergeConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value_stats: Option<ValueStatsConfig>,
+    #[serde(default)]
+    template_history: usize,
 
     clusters_counter: usize,
 
@@ -404,13 +1306,31 @@ pub struct SerializableDrain {
 impl From<&Drain> for SerializableDrain {
     fn from(drain: &Drain) -> Self {
         Self {
-            root_node: SerializableNode::from(&drain.root_node),
+            root_node: SerializableNode::from_length_nodes(&drain.root_node, &drain.interner),
+            partitions: drain
+                .partitions
+                .iter()
+                .map(|(k, v)| {
+                    (
+                        k.clone(),
+                        SerializableNode::from_length_nodes(v, &drain.interner),
+                    )
+                })
+                .collect(),
             log_cluster_depth: drain.log_cluster_depth,
             sim_th: drain.sim_th,
+            sim_th_by_length: drain.sim_th_by_length.clone(),
+            adaptive_sim_th: drain.adaptive_sim_th,
             max_children: drain.max_children,
             max_clusters: drain.max_clusters,
             extra_delimiters: drain.extra_delimiters.clone(),
             parametrize_numeric_tokens: drain.parametrize_numeric_tokens,
+            routing_predicates: drain.routing_predicates.clone(),
+            max_length_difference: drain.max_length_difference,
+            template_split: drain.template_split.clone(),
+            cluster_merge: drain.cluster_merge.clone(),
+            value_stats: drain.value_stats.clone(),
+            template_history: drain.template_history,
             clusters_counter: drain.clusters_counter,
 
             token_prefix: drain.token_prefix.clone(),
@@ -423,20 +1343,57 @@ impl From<&Drain> for SerializableDrain {
 
 impl From<SerializableDrain> for Drain {
     fn from(s: SerializableDrain) -> Self {
-        Self {
-            root_node: Node::from(s.root_node),
+        let mut interner = TokenInterner::new(&s.token_prefix, &s.token_suffix, &s.token_template);
+        let routing =
+            match RoutingPredicates::new(&s.routing_predicates, s.parametrize_numeric_tokens) {
+                Ok(x) => x,
+                Err(e) => {
+                    panic!("failed to create routing predicates, {}", e);
+                }
+            };
+        let mut drain = Self {
+            root_node: s.root_node.into_length_nodes(&mut interner),
+            partitions: s
+                .partitions
+                .into_iter()
+                .map(|(k, v)| (k, v.into_length_nodes(&mut interner)))
+                .collect(),
+            interner,
             log_cluster_depth: s.log_cluster_depth,
             sim_th: s.sim_th,
+            sim_th_by_length: s.sim_th_by_length,
+            adaptive_sim_th: s.adaptive_sim_th,
             max_children: s.max_children,
             max_clusters: s.max_clusters,
             extra_delimiters: s.extra_delimiters.clone(),
             parametrize_numeric_tokens: s.parametrize_numeric_tokens,
+            routing_predicates: s.routing_predicates,
+            routing,
+            max_length_difference: s.max_length_difference,
+            template_split: s.template_split,
+            split_events: Vec::new(),
+            cluster_merge: s.cluster_merge,
+            merge_events: Vec::new(),
+            messages_since_merge: 0,
+            value_stats: s.value_stats,
+            template_history: s.template_history,
+            tokenizer: Arc::new(WhitespaceTokenizer::new(&s.extra_delimiters)),
+            variable_clusters: Vec::new(),
             clusters_counter: s.clusters_counter,
 
             token_prefix: s.token_prefix.clone(),
             token_suffix: s.token_suffix.clone(),
             token_template: s.token_template.clone(),
             token_template_counter: s.token_template_counter,
+        };
+
+        if let Some(variable_id) = drain.interner.get(&drain.variable_token()) {
+            drain.variable_clusters = drain
+                .all_clusters()
+                .into_iter()
+                .filter(|c| c.lock().unwrap().token_ids.contains(&variable_id))
+                .collect();
         }
+        drain
     }
 }
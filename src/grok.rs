@@ -0,0 +1,209 @@
+use anyhow::{Result, anyhow, bail};
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::LazyLock;
+
+use crate::masking::MaskingInstructionConfig;
+
+static GROK_REFERENCE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"%\{(?P<name>[A-Za-z0-9_]+)(?::(?P<field>[A-Za-z0-9_.@\[\]-]+))?(?::(?:int|float))?\}",
+    )
+    .expect("failed to compile grok reference regex")
+});
+
+const MAX_EXPANSION_DEPTH: usize = 32;
+
+// A subset of the standard Logstash grok patterns, rewritten without lookaround
+// since the regex crate does not support it.
+const DEFAULT_PATTERNS: &str = r#"
+USERNAME [a-zA-Z0-9._-]+
+USER %{USERNAME}
+EMAILLOCALPART [a-zA-Z0-9!#$%&'*+/=?^_`{|}~-]+(?:\.[a-zA-Z0-9!#$%&'*+/=?^_`{|}~-]+)*
+EMAILADDRESS %{EMAILLOCALPART}@%{HOSTNAME}
+INT (?:[+-]?(?:[0-9]+))
+BASE10NUM [+-]?(?:[0-9]+(?:\.[0-9]+)?|\.[0-9]+)
+NUMBER (?:%{BASE10NUM})
+BASE16NUM (?:0[xX])?[0-9A-Fa-f]+
+BASE16FLOAT \b[+-]?(?:0[xX])?(?:[0-9A-Fa-f]+(?:\.[0-9A-Fa-f]*)?|\.[0-9A-Fa-f]+)\b
+POSINT \b(?:[1-9][0-9]*)\b
+NONNEGINT \b(?:[0-9]+)\b
+WORD \b\w+\b
+NOTSPACE \S+
+SPACE \s*
+DATA .*?
+GREEDYDATA .*
+QUOTEDSTRING "(?:\\.|[^\\"])*"|'(?:\\.|[^\\'])*'
+UUID [A-Fa-f0-9]{8}-(?:[A-Fa-f0-9]{4}-){3}[A-Fa-f0-9]{12}
+CISCOMAC (?:(?:[A-Fa-f0-9]{4}\.){2}[A-Fa-f0-9]{4})
+WINDOWSMAC (?:(?:[A-Fa-f0-9]{2}-){5}[A-Fa-f0-9]{2})
+COMMONMAC (?:(?:[A-Fa-f0-9]{2}:){5}[A-Fa-f0-9]{2})
+MAC (?:%{CISCOMAC}|%{WINDOWSMAC}|%{COMMONMAC})
+IPV6 (?:(?:[0-9A-Fa-f]{1,4}:){7}[0-9A-Fa-f]{1,4}|(?:[0-9A-Fa-f]{1,4}:){1,6}:[0-9A-Fa-f]{1,4}|(?:[0-9A-Fa-f]{1,4}:){1,5}(?::[0-9A-Fa-f]{1,4}){1,2}|(?:[0-9A-Fa-f]{1,4}:){1,4}(?::[0-9A-Fa-f]{1,4}){1,3}|(?:[0-9A-Fa-f]{1,4}:){1,3}(?::[0-9A-Fa-f]{1,4}){1,4}|(?:[0-9A-Fa-f]{1,4}:){1,2}(?::[0-9A-Fa-f]{1,4}){1,5}|[0-9A-Fa-f]{1,4}:(?::[0-9A-Fa-f]{1,4}){1,6}|(?:[0-9A-Fa-f]{1,4}:){1,7}:|:(?:(?::[0-9A-Fa-f]{1,4}){1,7}|:))
+IPV4 \b(?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\b
+IP (?:%{IPV6}|%{IPV4})
+HOSTNAME \b(?:[0-9A-Za-z][0-9A-Za-z-]{0,62})(?:\.(?:[0-9A-Za-z][0-9A-Za-z-]{0,62}))*\b
+IPORHOST (?:%{IP}|%{HOSTNAME})
+HOSTPORT %{IPORHOST}:%{POSINT}
+UNIXPATH (?:/[\w_%!$@:.,+~-]*)+
+WINPATH (?:[A-Za-z]+:|\\)(?:\\[^\\?*]*)+
+PATH (?:%{UNIXPATH}|%{WINPATH})
+URIPROTO [A-Za-z](?:[A-Za-z0-9+\-.]+)+
+LOGLEVEL (?:[Aa]lert|ALERT|[Tt]race|TRACE|[Dd]ebug|DEBUG|[Nn]otice|NOTICE|[Ii]nfo?(?:rmation)?|INFO?(?:RMATION)?|[Ww]arn?(?:ing)?|WARN?(?:ING)?|[Ee]rr?(?:or)?|ERR?(?:OR)?|[Cc]rit?(?:ical)?|CRIT?(?:ICAL)?|[Ff]atal|FATAL|[Ss]evere|SEVERE|EMERG(?:ENCY)?|[Ee]merg(?:ency)?)
+MONTH \b(?:[Jj]an(?:uary)?|[Ff]eb(?:ruary)?|[Mm]ar(?:ch)?|[Aa]pr(?:il)?|[Mm]ay|[Jj]un(?:e)?|[Jj]ul(?:y)?|[Aa]ug(?:ust)?|[Ss]ep(?:tember)?|[Oo]ct(?:ober)?|[Nn]ov(?:ember)?|[Dd]ec(?:ember)?)\b
+MONTHNUM (?:0?[1-9]|1[0-2])
+MONTHDAY (?:(?:0[1-9])|(?:[12][0-9])|(?:3[01])|[1-9])
+DAY (?:Mon(?:day)?|Tue(?:sday)?|Wed(?:nesday)?|Thu(?:rsday)?|Fri(?:day)?|Sat(?:urday)?|Sun(?:day)?)
+YEAR (?:\d\d){1,2}
+HOUR (?:2[0123]|[01]?[0-9])
+MINUTE (?:[0-5][0-9])
+SECOND (?:(?:[0-5]?[0-9]|60)(?:[:.,][0-9]+)?)
+TIME %{HOUR}:%{MINUTE}(?::%{SECOND})
+ISO8601_TIMEZONE (?:Z|[+-]%{HOUR}(?::?%{MINUTE}))
+TIMESTAMP_ISO8601 %{YEAR}-%{MONTHNUM}-%{MONTHDAY}[T ]%{HOUR}:?%{MINUTE}(?::?%{SECOND})?%{ISO8601_TIMEZONE}?
+SYSLOGTIMESTAMP %{MONTH} +%{MONTHDAY} %{TIME}
+"#;
+
+/// A library of named grok pattern definitions.
+#[derive(Debug, Clone)]
+pub struct GrokPatterns {
+    patterns: HashMap<String, String>,
+}
+
+impl Default for GrokPatterns {
+    fn default() -> Self {
+        let mut grok = Self::empty();
+        grok.load_definitions(DEFAULT_PATTERNS);
+        grok
+    }
+}
+
+impl GrokPatterns {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            patterns: HashMap::new(),
+        }
+    }
+
+    /// Loads the default patterns followed by each pattern file, later definitions
+    /// overriding earlier ones.
+    pub fn load(pattern_files: &[String]) -> Result<Self> {
+        let mut grok = Self::default();
+        for path in pattern_files {
+            grok.load_file(path)?;
+        }
+        Ok(grok)
+    }
+
+    pub fn load_file(&mut self, path: &str) -> Result<()> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read grok pattern file {}, {}", path, e))?;
+        self.load_definitions(&content);
+        Ok(())
+    }
+
+    /// Parses definitions in the Logstash pattern file format: one `NAME regex`
+    /// per line, `#` for comments.
+    pub fn load_definitions(&mut self, content: &str) {
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some((name, pattern)) = line.split_once(char::is_whitespace) {
+                self.add_pattern(name, pattern.trim());
+            }
+        }
+    }
+
+    pub fn add_pattern(&mut self, name: &str, pattern: &str) {
+        self.patterns.insert(name.to_string(), pattern.to_string());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.patterns.get(name).map(String::as_str)
+    }
+
+    /// Expands every `%{NAME}` / `%{NAME:field}` reference into a plain regex.
+    pub fn expand(&self, expression: &str) -> Result<String> {
+        self.expand_depth(expression, 0)
+    }
+
+    fn expand_depth(&self, expression: &str, depth: usize) -> Result<String> {
+        if depth > MAX_EXPANSION_DEPTH {
+            bail!("grok pattern expansion too deep in {}", expression);
+        }
+
+        let mut expanded = String::with_capacity(expression.len());
+        let mut last = 0;
+
+        for caps in GROK_REFERENCE.captures_iter(expression) {
+            let reference = caps.get(0).unwrap();
+            let name = &caps["name"];
+            let definition = self
+                .get(name)
+                .ok_or_else(|| anyhow!("unknown grok pattern {} in {}", name, expression))?;
+
+            expanded.push_str(&expression[last..reference.start()]);
+            expanded.push_str("(?:");
+            expanded.push_str(&self.expand_depth(definition, depth + 1)?);
+            expanded.push(')');
+            last = reference.end();
+        }
+        expanded.push_str(&expression[last..]);
+
+        Ok(expanded)
+    }
+
+    /// Returns the name a grok expression should mask with: its field name if it
+    /// has one, otherwise the referenced pattern name.
+    pub fn mask_name(expression: &str) -> Option<String> {
+        let mut references = GROK_REFERENCE.captures_iter(expression);
+        let caps = references.next()?;
+        if references.next().is_some() {
+            return None;
+        }
+        caps.name("field")
+            .or_else(|| caps.name("name"))
+            .map(|m| m.as_str().to_string())
+    }
+
+    /// Turns masking instruction configs that carry a `grok_pattern` into regex
+    /// masking instruction configs; other configs are passed through.
+    pub fn expand_masking_instructions(
+        &self,
+        configs: &[MaskingInstructionConfig],
+    ) -> Result<Vec<MaskingInstructionConfig>> {
+        configs
+            .iter()
+            .map(|config| {
+                let Some(grok_pattern) = &config.grok_pattern else {
+                    if config.pattern.is_empty() {
+                        bail!(
+                            "masking instruction {} requires regex_pattern or grok_pattern",
+                            config.mask_with
+                        );
+                    }
+                    return Ok(config.clone());
+                };
+
+                let mut expanded = config.clone();
+                expanded.pattern = self.expand(grok_pattern)?;
+                if expanded.mask_with.is_empty() {
+                    expanded.mask_with = Self::mask_name(grok_pattern).ok_or_else(|| {
+                        anyhow!(
+                            "cannot derive a mask name from grok pattern {}, mask_with is required",
+                            grok_pattern
+                        )
+                    })?;
+                }
+                Ok(expanded)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::cluster::LogCluster;
+use crate::interner::TokenInterner;
+
+/// A template and the more specific templates it generalizes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TemplateNode {
+    pub cluster_id: usize,
+    pub template: String,
+    pub size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partition: Option<String>,
+    pub children: Vec<TemplateNode>,
+}
+
+impl TemplateNode {
+    /// Messages of the template and of all templates below it.
+    pub fn total_size(&self) -> usize {
+        self.size
+            + self
+                .children
+                .iter()
+                .map(TemplateNode::total_size)
+                .sum::<usize>()
+    }
+
+    fn find(&self, cluster_id: usize) -> Option<&TemplateNode> {
+        if self.cluster_id == cluster_id {
+            return Some(self);
+        }
+        self.children
+            .iter()
+            .find_map(|child| child.find(cluster_id))
+    }
+}
+
+/// Templates arranged so that each is a child of the most specific template
+/// generalizing it, e.g. `user admin logged in` under `user <*> logged in`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TemplateHierarchy {
+    roots: Vec<TemplateNode>,
+    parents: HashMap<usize, usize>,
+}
+
+impl TemplateHierarchy {
+    /// Builds the hierarchy of clusters that share a prefix tree: a template's
+    /// parent is, of the templates of its partition and length generalizing it,
+    /// the one with the most tokens in common, the oldest on ties.
+    pub(crate) fn build(clusters: &[Arc<Mutex<LogCluster>>], interner: &TokenInterner) -> Self {
+        let mut clusters: Vec<LogCluster> = clusters
+            .iter()
+            .map(|cluster| cluster.lock().unwrap().clone())
+            .collect();
+        clusters.sort_by_key(|cluster| cluster.cluster_id);
+
+        let mut groups: BTreeMap<(Option<&str>, usize), Vec<&LogCluster>> = BTreeMap::new();
+        for cluster in &clusters {
+            groups
+                .entry((cluster.partition.as_deref(), cluster.token_ids.len()))
+                .or_default()
+                .push(cluster);
+        }
+
+        let mut parents = HashMap::new();
+        for group in groups.values() {
+            for child in group {
+                let parent = group
+                    .iter()
+                    .filter(|parent| generalizes(parent, child, interner))
+                    .max_by_key(|parent| {
+                        let common = parent
+                            .token_ids
+                            .iter()
+                            .zip(&child.token_ids)
+                            .filter(|(a, b)| a == b)
+                            .count();
+                        (common, std::cmp::Reverse(parent.cluster_id))
+                    });
+                if let Some(parent) = parent {
+                    parents.insert(child.cluster_id, parent.cluster_id);
+                }
+            }
+        }
+
+        let mut children: HashMap<usize, Vec<&LogCluster>> = HashMap::new();
+        let mut roots = Vec::new();
+        for cluster in &clusters {
+            match parents.get(&cluster.cluster_id) {
+                Some(parent_id) => children.entry(*parent_id).or_default().push(cluster),
+                None => roots.push(cluster),
+            }
+        }
+
+        Self {
+            roots: roots
+                .into_iter()
+                .map(|cluster| node(cluster, &children))
+                .collect(),
+            parents,
+        }
+    }
+
+    /// Templates no other template generalizes, by cluster id.
+    pub fn roots(&self) -> &[TemplateNode] {
+        &self.roots
+    }
+
+    pub fn get(&self, cluster_id: usize) -> Option<&TemplateNode> {
+        self.roots.iter().find_map(|root| root.find(cluster_id))
+    }
+
+    pub fn parent(&self, cluster_id: usize) -> Option<&TemplateNode> {
+        self.get(*self.parents.get(&cluster_id)?)
+    }
+
+    /// The templates from a root down to the cluster's, empty for unknown clusters.
+    pub fn path(&self, cluster_id: usize) -> Vec<&TemplateNode> {
+        let mut path: Vec<&TemplateNode> = Vec::new();
+        let mut cluster_id = Some(cluster_id);
+        while let Some(node) = cluster_id.and_then(|id| self.get(id)) {
+            path.push(node);
+            cluster_id = self.parents.get(&node.cluster_id).copied();
+        }
+        path.reverse();
+        path
+    }
+
+    /// The roots and, nested under each, its descendants.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&self.roots)?)
+    }
+}
+
+/// Whether `parent` accepts every token of `child` and has a wildcard where
+/// `child` has not, so that no two templates generalize each other.
+fn generalizes(parent: &LogCluster, child: &LogCluster, interner: &TokenInterner) -> bool {
+    let pairs = || parent.token_ids.iter().zip(&child.token_ids);
+    pairs().all(|(&p, &c)| interner.accepts(p, c))
+        && pairs().any(|(&p, &c)| interner.is_wildcard(p) && !interner.is_wildcard(c))
+}
+
+fn node(cluster: &LogCluster, children: &HashMap<usize, Vec<&LogCluster>>) -> TemplateNode {
+    TemplateNode {
+        cluster_id: cluster.cluster_id,
+        template: cluster.get_template(),
+        size: cluster.size,
+        partition: cluster.partition.clone(),
+        children: children
+            .get(&cluster.cluster_id)
+            .map(|clusters| {
+                clusters
+                    .iter()
+                    .map(|cluster| node(cluster, children))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
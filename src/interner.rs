@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use crate::drain::VARIABLE_LENGTH_MASK;
+
+pub type TokenId = u32;
+
+/// Id for tokens looked up without interning that were never seen, matching nothing.
+pub const UNKNOWN_TOKEN: TokenId = TokenId::MAX;
+
+/// Id of a template parameter set by `LogCluster::update_template`, which has no
+/// interner at hand; it stands for any token until the drain next updates the
+/// cluster and interns it.
+pub const STALE_PARAM: TokenId = TokenId::MAX - 1;
+
+/// Symbol table mapping each distinct token to a `TokenId`, so the prefix tree and
+/// similarity scoring compare integers instead of strings.
+#[derive(Debug, Clone, Default)]
+pub struct TokenInterner {
+    ids: HashMap<String, TokenId>,
+    tokens: Vec<String>,
+    params: Vec<bool>,
+    wildcards: Vec<bool>,
+    token_prefix: String,
+    token_suffix: String,
+    token_template: String,
+}
+
+impl TokenInterner {
+    /// Parameters named `token_template` followed by a number are those the drain
+    /// generates.
+    pub fn new(token_prefix: &str, token_suffix: &str, token_template: &str) -> Self {
+        Self {
+            ids: HashMap::new(),
+            tokens: Vec::new(),
+            params: Vec::new(),
+            wildcards: Vec::new(),
+            token_prefix: token_prefix.to_string(),
+            token_suffix: token_suffix.to_string(),
+            token_template: token_template.to_string(),
+        }
+    }
+
+    pub fn intern(&mut self, token: &str) -> TokenId {
+        if let Some(&id) = self.ids.get(token) {
+            return id;
+        }
+
+        let id = self.tokens.len() as TokenId;
+        self.ids.insert(token.to_string(), id);
+        self.tokens.push(token.to_string());
+        self.params.push(self.is_param_token(token));
+        self.wildcards.push(self.is_wildcard_token(token));
+        id
+    }
+
+    pub fn intern_all(&mut self, tokens: &[String]) -> Vec<TokenId> {
+        tokens.iter().map(|t| self.intern(t)).collect()
+    }
+
+    pub fn get(&self, token: &str) -> Option<TokenId> {
+        self.ids.get(token).copied()
+    }
+
+    /// Maps tokens to ids without interning, unseen tokens becoming `UNKNOWN_TOKEN`.
+    pub fn lookup_all(&self, tokens: &[String]) -> Vec<TokenId> {
+        tokens
+            .iter()
+            .map(|t| self.get(t).unwrap_or(UNKNOWN_TOKEN))
+            .collect()
+    }
+
+    pub fn resolve(&self, id: TokenId) -> &str {
+        &self.tokens[id as usize]
+    }
+
+    /// Whether the token is a parameter, i.e. wrapped in the token prefix and suffix.
+    pub fn is_param(&self, id: TokenId) -> bool {
+        id == STALE_PARAM || self.params.get(id as usize).copied().unwrap_or(false)
+    }
+
+    /// Whether the token is a parameter standing for any token: one the drain
+    /// generated, `*`, or the variable-length `...`. Other parameters, such as
+    /// masked values, are typed and only stand for themselves.
+    pub fn is_wildcard(&self, id: TokenId) -> bool {
+        id == STALE_PARAM || self.wildcards.get(id as usize).copied().unwrap_or(false)
+    }
+
+    /// Whether a template token accepts `token` at its position.
+    pub fn accepts(&self, template: TokenId, token: TokenId) -> bool {
+        template == token || self.is_wildcard(template)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    fn is_param_token(&self, token: &str) -> bool {
+        token.starts_with(&self.token_prefix) && token.ends_with(&self.token_suffix)
+    }
+
+    fn is_wildcard_token(&self, token: &str) -> bool {
+        let Some(name) = token
+            .strip_prefix(&self.token_prefix)
+            .and_then(|t| t.strip_suffix(&self.token_suffix))
+        else {
+            return false;
+        };
+        name == "*"
+            || name == VARIABLE_LENGTH_MASK
+            || name
+                .strip_prefix(&self.token_template)
+                .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+    }
+}
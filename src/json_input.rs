@@ -0,0 +1,83 @@
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Fields are JSON pointers such as `/msg` or `/log/message`; a bare name like
+/// `level` refers to a top-level field.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonInputConfig {
+    /// Field holding the message to mine.
+    #[serde(default = "default_message_field")]
+    pub message_field: String,
+    /// Fields carried through as metadata, keyed by the name they are configured with.
+    #[serde(default)]
+    pub metadata_fields: Vec<String>,
+    /// Field whose value messages are partitioned by, each partition mined apart.
+    #[serde(default)]
+    pub partition_field: Option<String>,
+}
+
+fn default_message_field() -> String {
+    "/message".to_string()
+}
+
+impl Default for JsonInputConfig {
+    fn default() -> Self {
+        Self {
+            message_field: default_message_field(),
+            metadata_fields: vec![],
+            partition_field: None,
+        }
+    }
+}
+
+/// A JSON log line split into the message to mine, its metadata and partition.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JsonLogLine {
+    pub message: String,
+    pub metadata: HashMap<String, String>,
+    pub partition: Option<String>,
+}
+
+impl JsonInputConfig {
+    pub fn parse(&self, line: &str) -> Result<JsonLogLine> {
+        let value: Value = serde_json::from_str(line)?;
+
+        let message = field_value(&value, &self.message_field)
+            .ok_or_else(|| anyhow!("JSON log line has no {} field", self.message_field))?;
+
+        let metadata = self
+            .metadata_fields
+            .iter()
+            .filter_map(|field| Some((field.clone(), field_value(&value, field)?)))
+            .collect();
+
+        let partition = self
+            .partition_field
+            .as_ref()
+            .and_then(|field| field_value(&value, field));
+
+        Ok(JsonLogLine {
+            message,
+            metadata,
+            partition,
+        })
+    }
+}
+
+/// Looks up a field, rendering non-string values as JSON. Missing and null fields
+/// have no value.
+fn field_value(value: &Value, field: &str) -> Option<String> {
+    let found = if field.starts_with('/') {
+        value.pointer(field)
+    } else {
+        value.get(field)
+    }?;
+
+    match found {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
@@ -1,11 +1,23 @@
 pub mod config;
 pub mod drain;
 pub mod file_persistence;
+pub mod grok;
+pub mod hierarchy;
+pub mod interner;
+pub mod json_input;
+pub mod log_format;
 pub mod masking;
+pub mod param_type;
 pub mod persistence;
+pub mod record_assembler;
+pub mod routing;
 pub mod template_miner;
+pub mod tokenizer;
+pub mod value_stats;
 
+mod alignment;
 mod cluster;
 mod tests;
 
-pub use cluster::{LogCluster, SearchStrategy, UpdateType};
+pub use cluster::{LogCluster, SearchStrategy, SimilarityStats, TemplateVersion, UpdateType};
+pub use param_type::ParamType;
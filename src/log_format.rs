@@ -0,0 +1,90 @@
+use anyhow::{Result, bail};
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+static FORMAT_FIELD: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<([^<>]+)>").expect("failed to compile log format field regex"));
+
+static SPACES: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r" +").expect("failed to compile spaces regex"));
+
+/// The field holding the part of a line that is mined.
+pub const CONTENT_FIELD: &str = "Content";
+
+/// A log line split into its mined content and its header fields.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParsedLogLine {
+    pub content: String,
+    pub metadata: HashMap<String, String>,
+}
+
+/// Header parser built from a LogPAI-style format spec such as
+/// `<Date> <Time> <Pid> <Level> <Component>: <Content>`.
+///
+/// Text between fields is matched literally, except that runs of spaces match
+/// any run of whitespace.
+#[derive(Debug, Clone)]
+pub struct LogFormat {
+    regex: Regex,
+    fields: Vec<String>,
+}
+
+impl LogFormat {
+    pub fn new(format: &str) -> Result<Self> {
+        let mut pattern = String::from("^");
+        let mut fields: Vec<String> = Vec::new();
+        let mut last = 0;
+
+        for caps in FORMAT_FIELD.captures_iter(format) {
+            let field = caps.get(0).unwrap();
+            let name = caps[1].to_string();
+            if fields.contains(&name) {
+                bail!("duplicate field <{}> in log format {}", name, format);
+            }
+
+            pattern.push_str(&Self::literal_regex(&format[last..field.start()]));
+            pattern.push_str("(.*?)");
+            fields.push(name);
+            last = field.end();
+        }
+        pattern.push_str(&Self::literal_regex(&format[last..]));
+        pattern.push('$');
+
+        if !fields.iter().any(|f| f == CONTENT_FIELD) {
+            bail!("log format {} has no <{}> field", format, CONTENT_FIELD);
+        }
+
+        Ok(Self {
+            regex: Regex::new(&pattern)?,
+            fields,
+        })
+    }
+
+    fn literal_regex(literal: &str) -> String {
+        SPACES
+            .replace_all(&regex::escape(literal), r"\s+")
+            .into_owned()
+    }
+
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// Splits a line into content and header fields, or `None` if it does not
+    /// follow the format.
+    pub fn parse(&self, line: &str) -> Option<ParsedLogLine> {
+        let captures = self.regex.captures(line)?;
+
+        let mut parsed = ParsedLogLine::default();
+        for (i, name) in self.fields.iter().enumerate() {
+            let value = captures.get(i + 1).map_or("", |m| m.as_str());
+            if name == CONTENT_FIELD {
+                parsed.content = value.to_string();
+            } else {
+                parsed.metadata.insert(name.clone(), value.to_string());
+            }
+        }
+        Some(parsed)
+    }
+}
@@ -1,18 +1,205 @@
-use regex::Regex;
+use anyhow::{Result, bail};
+use hmac::{Hmac, Mac};
+use lru::LruCache;
+use regex::{Captures, Regex};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+
+/// Pseudonyms a `PseudonymVault` keeps unless configured otherwise.
+pub const DEFAULT_VAULT_CAPACITY: usize = 100_000;
 
 pub trait AbstractMaskingInstruction {
     fn mask_with(&self) -> &str;
     fn mask(&self, content: &str, mask_prefix: &str, mask_suffix: &str) -> String;
     fn pattern(&self) -> &str;
+    fn examples(&self) -> &[MaskingExample] {
+        &[]
+    }
+    fn counter_examples(&self) -> &[String] {
+        &[]
+    }
+    /// Masks an example or counter example, without side effects such as
+    /// recording pseudonyms.
+    fn mask_example(&self, content: &str, mask_prefix: &str, mask_suffix: &str) -> String {
+        self.mask(content, mask_prefix, mask_suffix)
+    }
+    /// Transforms rewrite text in place and never produce a mask token.
+    fn is_transform(&self) -> bool {
+        false
+    }
+}
+
+/// An input and the output expected after applying a single masking instruction.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MaskingExample {
+    pub input: String,
+    pub expected: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaskingMode {
+    /// Replace matches with the `mask_with` label, e.g. `<:IP:>`.
+    #[default]
+    Mask,
+    /// Replace matches with a keyed-hash pseudonym, e.g. `EMAIL_3f9a1c2b5d6e7f80`.
+    Pseudonymize,
+    /// Rewrite matches according to `transform`, without producing a mask token.
+    Transform,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransformKind {
+    /// Replace matches with `replace_with`, which may reference capture groups.
+    #[default]
+    Replace,
+    Lowercase,
+    Uppercase,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct MaskingInstructionConfig {
-    #[serde(rename = "regex_pattern")]
+    #[serde(rename = "regex_pattern", default)]
     pub pattern: String,
+    /// Grok expression such as `%{IPV4:ip}`, expanded into `pattern` when loaded.
+    pub grok_pattern: Option<String>,
+    #[serde(default)]
     pub mask_with: String,
+    #[serde(default)]
+    pub mode: MaskingMode,
+    #[serde(default)]
+    pub transform: TransformKind,
+    #[serde(default)]
+    pub replace_with: String,
+    #[serde(default)]
+    pub examples: Vec<MaskingExample>,
+    /// Inputs the instruction must leave unchanged.
+    #[serde(default)]
+    pub counter_examples: Vec<String>,
+    /// Only apply when the message matches this regex.
+    pub guard_pattern: Option<String>,
+    /// Only apply to the text following the first occurrence of this literal.
+    pub after_prefix: Option<String>,
+    /// Only apply to tokens from this whitespace-separated position (0-based, inclusive).
+    pub token_start: Option<usize>,
+    /// Only apply to tokens before this whitespace-separated position (0-based, exclusive).
+    pub token_end: Option<usize>,
+}
+
+/// Restricts where in a message a masking instruction is applied.
+#[derive(Debug, Clone, Default)]
+pub struct MaskingScope {
+    pub guard: Option<Regex>,
+    pub after_prefix: Option<String>,
+    pub token_start: Option<usize>,
+    pub token_end: Option<usize>,
+}
+
+impl MaskingScope {
+    pub fn new(config: &MaskingInstructionConfig) -> Self {
+        let guard = config
+            .guard_pattern
+            .as_ref()
+            .map(|pattern| match Regex::new(pattern) {
+                Ok(x) => x,
+                Err(e) => {
+                    panic!("failed to compile guard regex {}, {}", pattern, e);
+                }
+            });
+        Self {
+            guard,
+            after_prefix: config.after_prefix.clone(),
+            token_start: config.token_start,
+            token_end: config.token_end,
+        }
+    }
+
+    pub fn is_unscoped(&self) -> bool {
+        self.guard.is_none()
+            && self.after_prefix.is_none()
+            && self.token_start.is_none()
+            && self.token_end.is_none()
+    }
+
+    /// Runs `mask` over the part of `content` in scope, leaving the rest untouched.
+    pub fn apply<F>(&self, content: &str, mask: F) -> String
+    where
+        F: FnOnce(&str) -> String,
+    {
+        if self.is_unscoped() {
+            return mask(content);
+        }
+
+        if let Some(guard) = &self.guard
+            && !guard.is_match(content)
+        {
+            return content.to_string();
+        }
+
+        let mut start = 0;
+        let mut end = content.len();
+
+        if let Some(prefix) = &self.after_prefix {
+            match content.find(prefix.as_str()) {
+                Some(idx) => start = idx + prefix.len(),
+                None => return content.to_string(),
+            }
+        }
+
+        if self.token_start.is_some() || self.token_end.is_some() {
+            match Self::token_span(
+                &content[start..],
+                self.token_start.unwrap_or(0),
+                self.token_end,
+            ) {
+                Some((span_start, span_end)) => {
+                    end = start + span_end;
+                    start += span_start;
+                }
+                None => return content.to_string(),
+            }
+        }
+
+        format!(
+            "{}{}{}",
+            &content[..start],
+            mask(&content[start..end]),
+            &content[end..]
+        )
+    }
+
+    fn token_span(
+        content: &str,
+        token_start: usize,
+        token_end: Option<usize>,
+    ) -> Option<(usize, usize)> {
+        let mut spans: Vec<(usize, usize)> = Vec::new();
+        let mut token_begin: Option<usize> = None;
+
+        for (i, c) in content.char_indices() {
+            if c.is_whitespace() {
+                if let Some(begin) = token_begin.take() {
+                    spans.push((begin, i));
+                }
+            } else if token_begin.is_none() {
+                token_begin = Some(i);
+            }
+        }
+        if let Some(begin) = token_begin {
+            spans.push((begin, content.len()));
+        }
+
+        let token_end = token_end.unwrap_or(spans.len()).min(spans.len());
+        if token_start >= token_end {
+            return None;
+        }
+
+        Some((spans[token_start].0, spans[token_end - 1].1))
+    }
 }
 
 #[derive(Clone)]
@@ -20,6 +207,9 @@ pub struct MaskingInstruction {
     pub pattern: String,
     pub mask_with: String,
     pub regex: Regex,
+    pub scope: MaskingScope,
+    pub examples: Vec<MaskingExample>,
+    pub counter_examples: Vec<String>,
 }
 
 impl MaskingInstruction {
@@ -34,6 +224,9 @@ impl MaskingInstruction {
             pattern: config.pattern.to_string(),
             mask_with: config.mask_with.to_string(),
             regex: re,
+            scope: MaskingScope::new(config),
+            examples: config.examples.clone(),
+            counter_examples: config.counter_examples.clone(),
         }
     }
 }
@@ -45,18 +238,260 @@ impl AbstractMaskingInstruction for MaskingInstruction {
 
     fn mask(&self, content: &str, mask_prefix: &str, mask_suffix: &str) -> String {
         let replacement = format!("{}{}{}", mask_prefix, self.mask_with, mask_suffix);
-        self.regex
-            .replace_all(content, replacement.as_str())
-            .to_string()
+        self.scope.apply(content, |region| {
+            self.regex
+                .replace_all(region, replacement.as_str())
+                .to_string()
+        })
     }
 
     fn pattern(&self) -> &str {
         &self.pattern
     }
+
+    fn examples(&self) -> &[MaskingExample] {
+        &self.examples
+    }
+
+    fn counter_examples(&self) -> &[String] {
+        &self.counter_examples
+    }
 }
 
 pub type RegexMaskingInstruction = MaskingInstruction;
 
+/// Normalizes matched text, e.g. lowercasing hex ids or stripping ANSI color codes.
+#[derive(Clone)]
+pub struct TransformInstruction {
+    pub pattern: String,
+    pub name: String,
+    pub regex: Regex,
+    pub transform: TransformKind,
+    pub replace_with: String,
+    pub scope: MaskingScope,
+    pub examples: Vec<MaskingExample>,
+    pub counter_examples: Vec<String>,
+}
+
+impl TransformInstruction {
+    pub fn new(config: &MaskingInstructionConfig) -> Self {
+        let re = match Regex::new(config.pattern.as_str()) {
+            Ok(x) => x,
+            Err(e) => {
+                panic!("failed to compile regex {}, {}", config.pattern, e);
+            }
+        };
+        Self {
+            pattern: config.pattern.to_string(),
+            name: config.mask_with.to_string(),
+            regex: re,
+            transform: config.transform,
+            replace_with: config.replace_with.to_string(),
+            scope: MaskingScope::new(config),
+            examples: config.examples.clone(),
+            counter_examples: config.counter_examples.clone(),
+        }
+    }
+}
+
+impl AbstractMaskingInstruction for TransformInstruction {
+    fn mask_with(&self) -> &str {
+        &self.name
+    }
+
+    fn mask(&self, content: &str, _mask_prefix: &str, _mask_suffix: &str) -> String {
+        self.scope.apply(content, |region| match self.transform {
+            TransformKind::Replace => self
+                .regex
+                .replace_all(region, self.replace_with.as_str())
+                .to_string(),
+            TransformKind::Lowercase => self
+                .regex
+                .replace_all(region, |caps: &Captures| caps[0].to_lowercase())
+                .to_string(),
+            TransformKind::Uppercase => self
+                .regex
+                .replace_all(region, |caps: &Captures| caps[0].to_uppercase())
+                .to_string(),
+        })
+    }
+
+    fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    fn examples(&self) -> &[MaskingExample] {
+        &self.examples
+    }
+
+    fn counter_examples(&self) -> &[String] {
+        &self.counter_examples
+    }
+
+    fn is_transform(&self) -> bool {
+        true
+    }
+}
+
+/// Reversible store of pseudonym -> original value, for authorized de-tokenization.
+/// Keeps the most recently seen pseudonyms, up to its capacity.
+#[derive(Debug)]
+pub struct PseudonymVault {
+    entries: Mutex<LruCache<String, String>>,
+}
+
+impl Default for PseudonymVault {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PseudonymVault {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_VAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    fn record(&self, pseudonym: &str, original: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.get(pseudonym).is_none() {
+            entries.put(pseudonym.to_string(), original.to_string());
+        }
+    }
+
+    pub fn reveal(&self, pseudonym: &str) -> Option<String> {
+        self.entries.lock().unwrap().peek(pseudonym).cloned()
+    }
+
+    pub fn detokenize(&self, content: &str) -> String {
+        let entries = self.entries.lock().unwrap();
+        let mut detokenized = content.to_string();
+        for (pseudonym, original) in entries.iter() {
+            if detokenized.contains(pseudonym.as_str()) {
+                detokenized = detokenized.replace(pseudonym.as_str(), original);
+            }
+        }
+        detokenized
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Replaces matches with `<mask_with>_<hmac>`, where the HMAC-SHA256 of the matched
+/// value is keyed so pseudonyms are stable across messages but not reversible
+/// without the vault.
+pub struct PseudonymizingMaskingInstruction {
+    pub pattern: String,
+    pub mask_with: String,
+    pub regex: Regex,
+    pub scope: MaskingScope,
+    pub examples: Vec<MaskingExample>,
+    pub counter_examples: Vec<String>,
+    key: Vec<u8>,
+    vault: Option<Arc<PseudonymVault>>,
+}
+
+impl PseudonymizingMaskingInstruction {
+    pub fn new(
+        config: &MaskingInstructionConfig,
+        key: &[u8],
+        vault: Option<Arc<PseudonymVault>>,
+    ) -> Self {
+        if key.is_empty() {
+            panic!(
+                "pseudonymization key is required for masking instruction {}",
+                config.mask_with
+            );
+        }
+        let re = match Regex::new(config.pattern.as_str()) {
+            Ok(x) => x,
+            Err(e) => {
+                panic!("failed to compile regex {}, {}", config.pattern, e);
+            }
+        };
+        Self {
+            pattern: config.pattern.to_string(),
+            mask_with: config.mask_with.to_string(),
+            regex: re,
+            scope: MaskingScope::new(config),
+            examples: config.examples.clone(),
+            counter_examples: config.counter_examples.clone(),
+            key: key.to_vec(),
+            vault,
+        }
+    }
+
+    pub fn pseudonym(&self, value: &str) -> String {
+        let digest = hmac_sha256(&self.key, value.as_bytes());
+        let hex: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{}_{}", self.mask_with, hex)
+    }
+
+    fn pseudonymize(&self, content: &str, vault: Option<&PseudonymVault>) -> String {
+        self.scope.apply(content, |region| {
+            self.regex
+                .replace_all(region, |caps: &Captures| {
+                    let value = &caps[0];
+                    let pseudonym = self.pseudonym(value);
+                    if let Some(vault) = vault {
+                        vault.record(&pseudonym, value);
+                    }
+                    pseudonym
+                })
+                .to_string()
+        })
+    }
+}
+
+impl AbstractMaskingInstruction for PseudonymizingMaskingInstruction {
+    fn mask_with(&self) -> &str {
+        &self.mask_with
+    }
+
+    fn mask(&self, content: &str, _mask_prefix: &str, _mask_suffix: &str) -> String {
+        self.pseudonymize(content, self.vault.as_deref())
+    }
+
+    fn mask_example(&self, content: &str, _mask_prefix: &str, _mask_suffix: &str) -> String {
+        self.pseudonymize(content, None)
+    }
+
+    fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    fn examples(&self) -> &[MaskingExample] {
+        &self.examples
+    }
+
+    fn counter_examples(&self) -> &[String] {
+        &self.counter_examples
+    }
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = match Hmac::<Sha256>::new_from_slice(key) {
+        Ok(x) => x,
+        Err(e) => {
+            panic!("failed to create hmac, {}", e);
+        }
+    };
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
 pub struct LogMasker {
     instructions: Vec<Box<dyn AbstractMaskingInstruction>>,
     pub mask_prefix: String,
@@ -70,21 +505,80 @@ impl LogMasker {
         mask_prefix: &str,
         mask_suffix: &str,
     ) -> Self {
+        match Self::try_new(instructions, mask_prefix, mask_suffix) {
+            Ok(x) => x,
+            Err(e) => {
+                panic!("{}", e);
+            }
+        }
+    }
+
+    /// Builds the masker and checks every instruction against its examples and
+    /// counter examples.
+    pub fn try_new(
+        instructions: Vec<Box<dyn AbstractMaskingInstruction>>,
+        mask_prefix: &str,
+        mask_suffix: &str,
+    ) -> Result<Self> {
+        for mi in &instructions {
+            Self::validate_instruction(mi.as_ref(), mask_prefix, mask_suffix)?;
+        }
+
         let mut mask_name_to_instructions: HashMap<String, Vec<usize>> = HashMap::new();
 
         for (i, mi) in instructions.iter().enumerate() {
+            if mi.is_transform() {
+                continue;
+            }
             mask_name_to_instructions
                 .entry(mi.mask_with().to_string())
                 .or_default()
                 .push(i);
         }
 
-        Self {
+        Ok(Self {
             instructions,
             mask_prefix: mask_prefix.to_string(),
             mask_suffix: mask_suffix.to_string(),
             mask_name_to_instructions,
+        })
+    }
+
+    fn validate_instruction(
+        mi: &dyn AbstractMaskingInstruction,
+        mask_prefix: &str,
+        mask_suffix: &str,
+    ) -> Result<()> {
+        for (i, example) in mi.examples().iter().enumerate() {
+            let masked = mi.mask_example(&example.input, mask_prefix, mask_suffix);
+            if masked != example.expected {
+                bail!(
+                    "masking instruction {} ({}) failed example {}: input {:?}, expected {:?}, got {:?}",
+                    mi.mask_with(),
+                    mi.pattern(),
+                    i + 1,
+                    example.input,
+                    example.expected,
+                    masked
+                );
+            }
         }
+
+        for (i, input) in mi.counter_examples().iter().enumerate() {
+            let masked = mi.mask_example(input, mask_prefix, mask_suffix);
+            if &masked != input {
+                bail!(
+                    "masking instruction {} ({}) failed counter example {}: input {:?} was masked to {:?}",
+                    mi.mask_with(),
+                    mi.pattern(),
+                    i + 1,
+                    input,
+                    masked
+                );
+            }
+        }
+
+        Ok(())
     }
 
     pub fn mask(&self, content: &str) -> String {
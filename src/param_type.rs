@@ -0,0 +1,157 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::LazyLock;
+use strum_macros::Display;
+
+static TIMESTAMP: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^(?:\d{4}-\d{2}-\d{2}(?:[T ]\d{2}:\d{2}(?::\d{2}(?:[.,]\d+)?)?(?:Z|[+-]\d{2}:?\d{2})?)?|\d{2}:\d{2}:\d{2}(?:[.,]\d+)?|\d{2}/[A-Za-z]{3}/\d{4}:\d{2}:\d{2}:\d{2}(?: [+-]\d{4})?)$",
+    )
+    .expect("failed to compile timestamp regex")
+});
+
+/// Most distinct values a wildcard position may take to be an enumeration.
+pub const ENUM_MAX_VALUES: usize = 8;
+
+/// Type of the values taken by a wildcard position.
+#[derive(
+    Clone, Copy, Debug, Display, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum ParamType {
+    Integer,
+    Float,
+    Ip,
+    Hex,
+    Uuid,
+    Timestamp,
+    /// A few recurring values, such as log levels or states.
+    Enum,
+    Text,
+}
+
+impl ParamType {
+    /// Type of a single value; never `Enum`, which only a set of values can be.
+    pub fn of(value: &str) -> Self {
+        if is_integer(value) {
+            Self::Integer
+        } else if is_float(value) {
+            Self::Float
+        } else if value.parse::<IpAddr>().is_ok() || value.parse::<SocketAddr>().is_ok() {
+            Self::Ip
+        } else if is_uuid(value) {
+            Self::Uuid
+        } else if is_hex(value) {
+            Self::Hex
+        } else if TIMESTAMP.is_match(value) {
+            Self::Timestamp
+        } else {
+            Self::Text
+        }
+    }
+}
+
+fn is_integer(value: &str) -> bool {
+    let digits = value.strip_prefix(['+', '-']).unwrap_or(value);
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn is_float(value: &str) -> bool {
+    value.bytes().any(|b| b.is_ascii_digit())
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_digit() || matches!(b, b'.' | b'e' | b'E' | b'+' | b'-'))
+        && value.parse::<f64>().is_ok()
+}
+
+fn is_uuid(value: &str) -> bool {
+    value.len() == 36
+        && value.bytes().enumerate().all(|(i, b)| match i {
+            8 | 13 | 18 | 23 => b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        })
+}
+
+/// `0x`-prefixed numbers, and hex strings long enough to mix digits and letters
+/// rather than spell a word.
+fn is_hex(value: &str) -> bool {
+    if let Some(digits) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        return !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_hexdigit());
+    }
+    value.len() >= 8
+        && value.bytes().all(|b| b.is_ascii_hexdigit())
+        && value.bytes().any(|b| b.is_ascii_digit())
+        && value.bytes().any(|b| b.is_ascii_alphabetic())
+}
+
+/// Values seen at a wildcard position, summarized to infer its type.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParamTypeStats {
+    /// How many values of each type were seen.
+    pub counts: BTreeMap<ParamType, usize>,
+    /// Distinct values, until there are more than `ENUM_MAX_VALUES`.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub values: BTreeSet<String>,
+    #[serde(default)]
+    pub overflowed: bool,
+}
+
+impl ParamTypeStats {
+    pub fn add(&mut self, value: &str, count: usize) {
+        *self.counts.entry(ParamType::of(value)).or_default() += count;
+        if self.overflowed {
+            return;
+        }
+        self.values.insert(value.to_string());
+        if self.values.len() > ENUM_MAX_VALUES {
+            self.overflowed = true;
+            self.values.clear();
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (&param_type, &count) in &other.counts {
+            *self.counts.entry(param_type).or_default() += count;
+        }
+        self.overflowed |= other.overflowed;
+        if self.overflowed {
+            self.values.clear();
+            return;
+        }
+        self.values.extend(other.values.iter().cloned());
+        if self.values.len() > ENUM_MAX_VALUES {
+            self.overflowed = true;
+            self.values.clear();
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    /// The narrowest type all values fit: integers widen to floats or hex, and
+    /// anything else mixed is free text, or an enumeration if a few values recur.
+    pub fn param_type(&self) -> ParamType {
+        let has_only = |allowed: &[ParamType]| self.counts.keys().all(|t| allowed.contains(t));
+
+        let types: Vec<ParamType> = self.counts.keys().copied().collect();
+        match types.as_slice() {
+            [] => return ParamType::Text,
+            &[only] if only != ParamType::Text => return only,
+            _ if has_only(&[ParamType::Integer, ParamType::Float]) => return ParamType::Float,
+            _ if has_only(&[ParamType::Integer, ParamType::Hex]) => return ParamType::Hex,
+            _ => {}
+        }
+
+        // Values must recur to tell an enumeration from a few distinct words.
+        if !self.overflowed && self.count() >= 2 * self.values.len() {
+            ParamType::Enum
+        } else {
+            ParamType::Text
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{Result, anyhow};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultilineConfig {
+    /// A line matching any of these starts a new record. When set, every other line
+    /// continues the open record.
+    #[serde(default)]
+    pub start_patterns: Vec<String>,
+    /// A line matching any of these continues the open record.
+    #[serde(default = "default_continuation_patterns")]
+    pub continuation_patterns: Vec<String>,
+    /// A record reaching this many lines is emitted without waiting for the next one.
+    #[serde(default = "default_max_lines")]
+    pub max_lines: usize,
+    /// A record not continued within this time is emitted on `poll`.
+    #[serde(default = "default_flush_timeout_ms")]
+    pub flush_timeout_ms: u64,
+}
+
+fn default_continuation_patterns() -> Vec<String> {
+    vec![
+        r"^\s".to_string(),
+        r"^Caused by:".to_string(),
+        r"^\.\.\. \d+ more".to_string(),
+    ]
+}
+
+fn default_max_lines() -> usize {
+    500
+}
+
+fn default_flush_timeout_ms() -> u64 {
+    1000
+}
+
+impl Default for MultilineConfig {
+    fn default() -> Self {
+        Self {
+            start_patterns: vec![],
+            continuation_patterns: default_continuation_patterns(),
+            max_lines: default_max_lines(),
+            flush_timeout_ms: default_flush_timeout_ms(),
+        }
+    }
+}
+
+/// A multi-line event: its first line is mined, the rest is carried as payload.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogRecord {
+    pub first_line: String,
+    pub payload: Vec<String>,
+}
+
+impl LogRecord {
+    pub fn new(first_line: &str) -> Self {
+        Self {
+            first_line: first_line.to_string(),
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn line_count(&self) -> usize {
+        1 + self.payload.len()
+    }
+}
+
+/// Groups lines such as stack traces into records.
+#[derive(Debug)]
+pub struct RecordAssembler {
+    start_patterns: Vec<Regex>,
+    continuation_patterns: Vec<Regex>,
+    max_lines: usize,
+    flush_timeout: Duration,
+    pending: Option<LogRecord>,
+    last_line_time: Instant,
+}
+
+impl RecordAssembler {
+    pub fn new(config: &MultilineConfig) -> Result<Self> {
+        let compile = |patterns: &[String]| -> Result<Vec<Regex>> {
+            patterns
+                .iter()
+                .map(|p| {
+                    Regex::new(p).map_err(|e| anyhow!("invalid multiline pattern {}, {}", p, e))
+                })
+                .collect()
+        };
+
+        Ok(Self {
+            start_patterns: compile(&config.start_patterns)?,
+            continuation_patterns: compile(&config.continuation_patterns)?,
+            max_lines: config.max_lines.max(1),
+            flush_timeout: Duration::from_millis(config.flush_timeout_ms),
+            pending: None,
+            last_line_time: Instant::now(),
+        })
+    }
+
+    fn is_continuation(&self, line: &str) -> bool {
+        self.continuation_patterns
+            .iter()
+            .any(|re| re.is_match(line))
+            || (!self.start_patterns.is_empty()
+                && !self.start_patterns.iter().any(|re| re.is_match(line)))
+    }
+
+    /// Adds a line, returning the record it completes, if any.
+    pub fn push(&mut self, line: &str) -> Option<LogRecord> {
+        self.last_line_time = Instant::now();
+
+        let completed = if self.pending.is_some() && self.is_continuation(line) {
+            if let Some(record) = self.pending.as_mut() {
+                record.payload.push(line.to_string());
+            }
+            None
+        } else {
+            self.pending.replace(LogRecord::new(line))
+        };
+
+        if completed.is_none()
+            && self
+                .pending
+                .as_ref()
+                .is_some_and(|r| r.line_count() >= self.max_lines)
+        {
+            return self.pending.take();
+        }
+        completed
+    }
+
+    /// Emits the open record if it has waited longer than the flush timeout.
+    pub fn poll(&mut self) -> Option<LogRecord> {
+        if self.last_line_time.elapsed() >= self.flush_timeout {
+            self.pending.take()
+        } else {
+            None
+        }
+    }
+
+    /// Emits the open record, e.g. at the end of input.
+    pub fn flush(&mut self) -> Option<LogRecord> {
+        self.pending.take()
+    }
+}
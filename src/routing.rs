@@ -0,0 +1,94 @@
+use anyhow::{Result, anyhow};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::param_type::ParamType;
+use crate::tokenizer::Tokenizer;
+
+/// A test sending matching tokens to the wildcard child of prefix tree nodes, so
+/// that they never get an exact-match branch of their own. Masked values and
+/// parameters, such as `<IP>`, are always routed there.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoutingPredicateConfig {
+    /// `numeric`, `hex_like`, `path_like`, `long_token` or `regex`.
+    pub kind: String,
+    /// Pattern a token must match as a whole for the `regex` kind.
+    #[serde(default)]
+    pub pattern: String,
+    /// Length in characters from which the `long_token` kind matches.
+    #[serde(default = "default_min_length")]
+    pub min_length: usize,
+}
+
+fn default_min_length() -> usize {
+    32
+}
+
+impl RoutingPredicateConfig {
+    pub fn new(kind: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            pattern: String::new(),
+            min_length: default_min_length(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum RoutingPredicate {
+    /// Has a digit, as the tokenizer defines them.
+    Numeric,
+    /// Hex numbers, hashes and UUIDs.
+    HexLike,
+    /// File paths and URLs.
+    PathLike,
+    LongToken(usize),
+    Regex(Regex),
+}
+
+/// The routing predicates of a drain; a token matching any of them is routed to
+/// the wildcard child.
+#[derive(Debug, Clone)]
+pub struct RoutingPredicates {
+    predicates: Vec<RoutingPredicate>,
+}
+
+impl RoutingPredicates {
+    /// With `parametrize_numeric_tokens`, a `numeric` predicate is implied.
+    pub fn new(
+        configs: &[RoutingPredicateConfig],
+        parametrize_numeric_tokens: bool,
+    ) -> Result<Self> {
+        let mut predicates = Vec::with_capacity(configs.len() + 1);
+        if parametrize_numeric_tokens {
+            predicates.push(RoutingPredicate::Numeric);
+        }
+        for config in configs {
+            let predicate = match config.kind.as_str() {
+                "numeric" => RoutingPredicate::Numeric,
+                "hex_like" => RoutingPredicate::HexLike,
+                "path_like" => RoutingPredicate::PathLike,
+                "long_token" => RoutingPredicate::LongToken(config.min_length),
+                "regex" => {
+                    RoutingPredicate::Regex(Regex::new(&format!("^(?:{})$", config.pattern))?)
+                }
+                kind => return Err(anyhow!("unknown routing predicate kind {}", kind)),
+            };
+            predicates.push(predicate);
+        }
+
+        Ok(Self { predicates })
+    }
+
+    pub fn matches(&self, token: &str, tokenizer: &dyn Tokenizer) -> bool {
+        self.predicates.iter().any(|predicate| match predicate {
+            RoutingPredicate::Numeric => tokenizer.has_numbers(token),
+            RoutingPredicate::HexLike => {
+                matches!(ParamType::of(token), ParamType::Hex | ParamType::Uuid)
+            }
+            RoutingPredicate::PathLike => token.len() > 1 && token.contains(['/', '\\']),
+            RoutingPredicate::LongToken(min_length) => token.chars().count() >= *min_length,
+            RoutingPredicate::Regex(regex) => regex.is_match(token),
+        })
+    }
+}
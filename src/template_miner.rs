@@ -1,31 +1,75 @@
 use crate::cluster::{LogCluster, SearchStrategy, UpdateType};
 use crate::config::TemplateMinerConfig;
-use crate::drain::{Drain, DrainConfig, SerializableDrain};
-use crate::masking::{AbstractMaskingInstruction, LogMasker, MaskingInstruction};
+use crate::drain::{Drain, DrainConfig, MergeEvent, SerializableDrain, VARIABLE_LENGTH_MASK};
+use crate::grok::GrokPatterns;
+use crate::json_input::JsonLogLine;
+use crate::log_format::{LogFormat, ParsedLogLine};
+use crate::masking::{
+    AbstractMaskingInstruction, LogMasker, MaskingInstruction, MaskingMode, PseudonymVault,
+    PseudonymizingMaskingInstruction, TransformInstruction,
+};
+use crate::param_type::ParamType;
 use crate::persistence::PersistenceHandler;
+use crate::record_assembler::{LogRecord, RecordAssembler};
+use crate::tokenizer::{self, Tokenizer};
 use anyhow::Result;
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use regex::Regex;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::ops::Range;
 
 #[derive(Clone, Debug)]
 pub struct ExtractedParameter {
     pub value: String,
     pub mask_name: String,
+    /// Key of a `key=value` pair the parameter is the value of.
+    pub key: Option<String>,
+    /// Byte offsets of the parameter in the message it was extracted from; `None`
+    /// when the tokenizer rewrote the tokens holding it.
+    pub span: Option<Range<usize>>,
+    /// Index of the template token holding the parameter.
+    pub position: usize,
+    /// The message text at `span`, when tokenization changed it from `value`, such
+    /// as an extra delimiter joined as a space.
+    pub original: Option<String>,
+    /// Type of the value, or of the wildcard when extracted with a cluster.
+    pub param_type: ParamType,
 }
 
 impl ExtractedParameter {
     pub fn new(value: String, mask_name: String) -> Self {
-        Self { value, mask_name }
+        Self {
+            param_type: ParamType::of(&value),
+            value,
+            mask_name,
+            key: None,
+            span: None,
+            position: 0,
+            original: None,
+        }
     }
 }
 
+/// Result of mining a raw log line: the cluster of its content and its header fields.
+#[derive(Clone, Debug)]
+pub struct MinedLogLine {
+    pub cluster: Option<Arc<Mutex<LogCluster>>>,
+    pub update_type: UpdateType,
+    pub metadata: HashMap<String, String>,
+    /// Lines following the mined line in a multi-line record.
+    pub payload: Vec<String>,
+}
+
 pub struct TemplateMiner<'a> {
     pub config: &'a TemplateMinerConfig,
     pub drain: Drain,
     pub masker: LogMasker,
+    pub pseudonym_vault: Option<Arc<PseudonymVault>>,
+    tokenizer: Arc<dyn Tokenizer>,
+    log_format: Option<LogFormat>,
+    record_assembler: Option<RecordAssembler>,
     persistence_handler: Option<Box<dyn PersistenceHandler>>,
     last_save_time: u64,
     state_dirty: bool,
@@ -36,23 +80,64 @@ impl<'a> TemplateMiner<'a> {
         config: &'a TemplateMinerConfig,
         persistence_handler: Option<Box<dyn PersistenceHandler>>,
     ) -> Self {
-        let drain = Drain::new(&DrainConfig {
-            log_cluster_depth: config.drain_depth,
-            sim_th: config.drain_sim_th,
-            max_children: config.drain_max_children,
-            max_clusters: config.drain_max_clusters,
-            extra_delimiters: config.drain_extra_delimiters.clone(),
-            parametrize_numeric_tokens: config.parametrize_numeric_tokens,
-            token_prefix: config.mask_prefix.clone(),
-            token_suffix: config.mask_suffix.clone(),
-            token_template: config.token_template.clone(),
-        });
-
-        let masking_instructions = config
-            .masking_instructions
+        let mut drain = Drain::new(&drain_config(config));
+
+        let tokenizer =
+            match tokenizer::create_tokenizer(&config.tokenizer, &config.drain_extra_delimiters) {
+                Ok(x) => x,
+                Err(e) => {
+                    panic!("failed to create tokenizer, {}", e);
+                }
+            };
+        drain.set_tokenizer(tokenizer.clone());
+
+        let log_format = match config.log_format.as_deref().map(LogFormat::new).transpose() {
+            Ok(x) => x,
+            Err(e) => {
+                panic!("failed to parse log format, {}", e);
+            }
+        };
+
+        let record_assembler = match config
+            .multiline
+            .as_ref()
+            .map(RecordAssembler::new)
+            .transpose()
+        {
+            Ok(x) => x,
+            Err(e) => {
+                panic!("failed to create record assembler, {}", e);
+            }
+        };
+
+        let pseudonym_vault = if config.pseudonymization_vault {
+            Some(Arc::new(PseudonymVault::with_capacity(
+                config.pseudonymization_vault_capacity,
+            )))
+        } else {
+            None
+        };
+
+        let masking_instruction_configs = match GrokPatterns::load(&config.grok_pattern_files)
+            .and_then(|grok| grok.expand_masking_instructions(&config.masking_instructions))
+        {
+            Ok(x) => x,
+            Err(e) => {
+                panic!("failed to load masking instructions, {}", e);
+            }
+        };
+
+        let masking_instructions = masking_instruction_configs
             .iter()
-            .map(|config| {
-                Box::new(MaskingInstruction::new(config)) as Box<dyn AbstractMaskingInstruction>
+            .map(|mi_config| match mi_config.mode {
+                MaskingMode::Mask => Box::new(MaskingInstruction::new(mi_config))
+                    as Box<dyn AbstractMaskingInstruction>,
+                MaskingMode::Pseudonymize => Box::new(PseudonymizingMaskingInstruction::new(
+                    mi_config,
+                    config.pseudonymization_key.as_bytes(),
+                    pseudonym_vault.clone(),
+                )),
+                MaskingMode::Transform => Box::new(TransformInstruction::new(mi_config)),
             })
             .collect();
 
@@ -66,6 +151,10 @@ impl<'a> TemplateMiner<'a> {
             config,
             drain,
             masker,
+            pseudonym_vault,
+            tokenizer,
+            log_format,
+            record_assembler,
             persistence_handler,
             last_save_time: Self::current_time_sec(),
             state_dirty: false,
@@ -89,10 +178,23 @@ impl<'a> TemplateMiner<'a> {
         &mut self,
         log_message: &str,
     ) -> (Option<Arc<Mutex<LogCluster>>>, UpdateType) {
-        let masked_content = self.masker.mask(log_message);
-        let (cluster, change_type) = self.drain.add_log_message(&masked_content);
+        self.add_partitioned_log_message(log_message, None)
+    }
 
-        self.state_dirty = self.state_dirty || change_type != UpdateType::None;
+    pub fn add_partitioned_log_message(
+        &mut self,
+        log_message: &str,
+        partition: Option<&str>,
+    ) -> (Option<Arc<Mutex<LogCluster>>>, UpdateType) {
+        let masked_content = self.masker.mask(log_message);
+        let merge_count = self.drain.merge_events().len();
+        let (cluster, change_type) = self
+            .drain
+            .add_partitioned_log_message(&masked_content, partition);
+
+        self.state_dirty = self.state_dirty
+            || change_type != UpdateType::None
+            || self.drain.merge_events().len() != merge_count;
         if self.persistence_handler.is_some()
             && self.should_save_state()
             && let Err(e) = self.save_state()
@@ -103,6 +205,93 @@ impl<'a> TemplateMiner<'a> {
         (cluster, change_type)
     }
 
+    /// Splits a raw line with the configured log format. Lines without a format, or
+    /// not following it, are taken whole as content.
+    pub fn parse_log_line(&self, line: &str) -> ParsedLogLine {
+        self.log_format
+            .as_ref()
+            .and_then(|format| format.parse(line))
+            .unwrap_or_else(|| ParsedLogLine {
+                content: line.to_string(),
+                metadata: HashMap::new(),
+            })
+    }
+
+    /// Mines only the content of a raw line, returning its header fields as metadata.
+    pub fn add_log_line(&mut self, line: &str) -> MinedLogLine {
+        let parsed = self.parse_log_line(line);
+        let (cluster, update_type) = self.add_log_message(&parsed.content);
+        MinedLogLine {
+            cluster,
+            update_type,
+            metadata: parsed.metadata,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Mines the first line of a record, carrying the remaining lines as payload.
+    pub fn add_log_record(&mut self, record: LogRecord) -> MinedLogLine {
+        let mut mined = self.add_log_line(&record.first_line);
+        mined.payload = record.payload;
+        mined
+    }
+
+    /// Feeds a line to the multi-line record assembler, mining the record it completes.
+    /// Without a `multiline` config every line is mined right away.
+    pub fn push_log_line(&mut self, line: &str) -> Option<MinedLogLine> {
+        match self.record_assembler.as_mut() {
+            Some(assembler) => {
+                let record = assembler.push(line)?;
+                Some(self.add_log_record(record))
+            }
+            None => Some(self.add_log_line(line)),
+        }
+    }
+
+    /// Mines the open record if it exceeded the flush timeout.
+    pub fn poll_log_record(&mut self) -> Option<MinedLogLine> {
+        let record = self.record_assembler.as_mut()?.poll()?;
+        Some(self.add_log_record(record))
+    }
+
+    /// Mines the open record, e.g. at the end of input.
+    pub fn flush_log_record(&mut self) -> Option<MinedLogLine> {
+        let record = self.record_assembler.as_mut()?.flush()?;
+        Some(self.add_log_record(record))
+    }
+
+    pub fn parse_json_log_line(&self, line: &str) -> Result<JsonLogLine> {
+        self.config.json_input.parse(line)
+    }
+
+    /// Mines the message field of a JSON log line within its partition, returning the
+    /// configured metadata fields.
+    pub fn add_json_log_line(&mut self, line: &str) -> Result<MinedLogLine> {
+        let parsed = self.parse_json_log_line(line)?;
+        let (cluster, update_type) =
+            self.add_partitioned_log_message(&parsed.message, parsed.partition.as_deref());
+        Ok(MinedLogLine {
+            cluster,
+            update_type,
+            metadata: parsed.metadata,
+            payload: Vec::new(),
+        })
+    }
+
+    pub fn match_json_cluster(
+        &self,
+        line: &str,
+        strategy: SearchStrategy,
+    ) -> Result<Option<Arc<Mutex<LogCluster>>>> {
+        let parsed = self.parse_json_log_line(line)?;
+        let masked_content = self.masker.mask(&parsed.message);
+        Ok(self.drain.match_partitioned_cluster(
+            &masked_content,
+            parsed.partition.as_deref(),
+            strategy,
+        ))
+    }
+
     pub fn match_cluster(
         &self,
         content: &str,
@@ -112,6 +301,41 @@ impl<'a> TemplateMiner<'a> {
         self.drain.match_cluster(masked_content.as_str(), strategy)
     }
 
+    /// Resets the miner to a corpus: the clusters mined so far and the template
+    /// counter are discarded, and the persisted state is overwritten on the next
+    /// save. The corpus is mined with templates that don't depend on the order of
+    /// `log_messages`, see `Drain::rebuild_from_batch`. Returns the cluster of
+    /// each message.
+    pub fn rebuild_from_batch(
+        &mut self,
+        log_messages: &[&str],
+    ) -> Vec<Option<Arc<Mutex<LogCluster>>>> {
+        let masked_contents: Vec<String> = log_messages
+            .iter()
+            .map(|log_message| self.masker.mask(log_message))
+            .collect();
+        let masked_contents: Vec<&str> = masked_contents.iter().map(String::as_str).collect();
+
+        let clusters = self.drain.rebuild_from_batch(&masked_contents);
+
+        self.state_dirty = true;
+        if self.persistence_handler.is_some()
+            && self.should_save_state()
+            && let Err(e) = self.save_state()
+        {
+            eprintln!("Failed to save state: {}", e);
+        }
+
+        clusters
+    }
+
+    /// Runs a merge pass on demand, see `Drain::merge_clusters`.
+    pub fn merge_clusters(&mut self) -> Vec<MergeEvent> {
+        let events = self.drain.merge_clusters();
+        self.state_dirty = self.state_dirty || !events.is_empty();
+        events
+    }
+
     fn should_save_state(&self) -> bool {
         Self::current_time_sec() - self.last_save_time >= self.config.snapshot_interval_minutes * 60
             && self.state_dirty
@@ -133,6 +357,7 @@ impl<'a> TemplateMiner<'a> {
         {
             let ser_drain: SerializableDrain = serde_json::from_slice(&state)?;
             self.drain = Drain::from(ser_drain);
+            self.drain.set_tokenizer(self.tokenizer.clone());
         }
         Ok(())
     }
@@ -150,49 +375,124 @@ impl<'a> TemplateMiner<'a> {
         log_message: &str,
         exact_matching: bool,
     ) -> Option<Vec<ExtractedParameter>> {
-        let mut normalized = log_message.to_string();
+        let key_value_separator = self.tokenizer.key_value_separator();
+        let message_tokens = self.drain.get_content_as_tokens(log_message);
+        let (normalized, normalized_offsets) =
+            tokenizer::join_tokens_with_offsets(&message_tokens, key_value_separator);
+        let message_offsets = locate_tokens(log_message, &message_tokens);
 
-        for delimiter in &self.config.drain_extra_delimiters {
-            let re = Regex::new(delimiter).ok()?;
-            normalized = re.replace_all(&normalized, " ").into_owned();
-        }
-
-        let (template_regex, param_map) =
-            self.get_template_parameter_extraction_regex(log_template, exact_matching);
+        let (template_regex, template_params) =
+            self.build_parameter_extraction_regex(log_template, exact_matching);
 
         let re = Regex::new(&template_regex).ok()?;
 
         let captures = re.captures(&normalized)?;
 
+        let template_tokens = self.drain.get_content_as_tokens(log_template);
+        let template_offsets = locate_tokens(log_template, &template_tokens);
+
         let mut extracted = Vec::new();
 
-        for (group_name, mask_name) in param_map {
-            if let Some(value) = captures.name(&group_name) {
-                extracted.push(ExtractedParameter::new(
-                    value.as_str().to_string(),
-                    mask_name,
-                ));
+        for param in template_params {
+            if let Some(value) = captures.name(&param.group_name) {
+                let mut parameter =
+                    ExtractedParameter::new(value.as_str().to_string(), param.mask_name);
+                if let Some(separator) = key_value_separator {
+                    parameter.key = normalized[..value.start()]
+                        .strip_suffix(separator)
+                        .and_then(|before| before.split_whitespace().last())
+                        .map(|key| key.to_string());
+                }
+
+                let start = map_offset(
+                    value.start(),
+                    false,
+                    &normalized_offsets,
+                    log_message,
+                    &message_offsets,
+                    &message_tokens,
+                );
+                let end = map_offset(
+                    value.end(),
+                    true,
+                    &normalized_offsets,
+                    log_message,
+                    &message_offsets,
+                    &message_tokens,
+                );
+                if let (Some(start), Some(end)) = (start, end) {
+                    let span = start..end.max(start);
+                    let original = &log_message[span.clone()];
+                    if original != parameter.value {
+                        parameter.original = Some(original.to_string());
+                    }
+                    parameter.span = Some(span);
+                }
+                // A key with no value is tokenized with a placeholder value, which
+                // stands for an empty one.
+                if let Some(at) = empty_value_at(
+                    value.range(),
+                    &normalized_offsets,
+                    log_message,
+                    &message_offsets,
+                    &message_tokens,
+                ) {
+                    parameter.value.clear();
+                    parameter.span = Some(at..at);
+                    parameter.original = None;
+                }
+                parameter.position = template_offsets
+                    .iter()
+                    .rposition(|&offset| offset <= param.template_offset)
+                    .unwrap_or(0);
+
+                extracted.push(parameter);
             }
         }
 
         Some(extracted)
     }
 
+    /// Extracts parameters with the cluster's template, typing each with the type
+    /// its wildcard position was inferred to have.
+    pub fn extract_cluster_parameters(
+        &self,
+        cluster: &LogCluster,
+        log_message: &str,
+        exact_matching: bool,
+    ) -> Option<Vec<ExtractedParameter>> {
+        let mut extracted =
+            self.extract_parameters(&cluster.get_template(), log_message, exact_matching)?;
+        for parameter in &mut extracted {
+            if let Some(param_type) = cluster.param_type(parameter.position) {
+                parameter.param_type = param_type;
+            }
+        }
+        Some(extracted)
+    }
+
     pub fn get_template_parameter_extraction_regex(
         &self,
         log_template: &str,
         exact_matching: bool,
     ) -> (String, HashMap<String, String>) {
-        let mut param_map: HashMap<String, String> = HashMap::new();
-        let mut counter: usize = 0;
-
-        let mut get_next_param_name = || {
-            let name = format!("p_{}", counter);
-            counter += 1;
-            name
-        };
+        let (template_regex, template_params) =
+            self.build_parameter_extraction_regex(log_template, exact_matching);
+        let param_map = template_params
+            .into_iter()
+            .map(|p| (p.group_name, p.mask_name))
+            .collect();
+        (template_regex, param_map)
+    }
 
-        let mut create_capture_regex = |mask_name: &str| -> String {
+    /// Builds the extraction regex with one capture group per mask token of the
+    /// template, listed in template order.
+    fn build_parameter_extraction_regex(
+        &self,
+        log_template: &str,
+        exact_matching: bool,
+    ) -> (String, Vec<TemplateParameter>) {
+        let create_capture_regex = |mask_name: &str, param_name: &str| -> String {
             let mut allowed_patterns: Vec<String> = Vec::new();
 
             if exact_matching {
@@ -214,45 +514,77 @@ impl<'a> TemplateMiner<'a> {
                 allowed_patterns.push(".+?".to_string());
             }
 
-            let param_name = get_next_param_name();
-
-            param_map.insert(param_name.clone(), mask_name.to_string());
-
             let joined = allowed_patterns.join("|");
 
             format!("(?P<{}>{})", param_name, joined)
         };
 
-        let mut mask_names: HashSet<String> = self.masker.mask_names().iter().cloned().collect();
-
-        mask_names.insert("*".to_string());
-
-        let escaped_prefix = regex::escape(&self.masker.mask_prefix);
-
-        let escaped_suffix = regex::escape(&self.masker.mask_suffix);
-
-        let mut template_regex = regex::escape(log_template);
-
-        for mask_name in mask_names {
-            let search_str = format!(
-                "{}{}{}",
-                escaped_prefix,
-                regex::escape(&mask_name),
-                escaped_suffix
-            );
-
-            loop {
-                let rep = create_capture_regex(&mask_name);
-
-                let new = template_regex.replacen(&search_str, &rep, 1);
-
-                if new == template_regex {
-                    break;
+        let mut mask_names: Vec<String> = self.masker.mask_names().to_vec();
+        mask_names.push("*".to_string());
+        mask_names.push(VARIABLE_LENGTH_MASK.to_string());
+
+        let mut mask_name_patterns: Vec<String> =
+            mask_names.iter().map(|name| regex::escape(name)).collect();
+        // Parameters the drain generated, such as `<TOKEN3>`, capture anything.
+        let generated_token = Regex::new(&format!(
+            r"^{}\d+$",
+            regex::escape(self.drain.token_template())
+        ))
+        .expect("failed to compile generated token regex");
+        mask_name_patterns.push(format!(
+            r"{}\d+",
+            regex::escape(self.drain.token_template())
+        ));
+
+        let mask_token_regex = Regex::new(&format!(
+            "{}({}){}",
+            regex::escape(&self.masker.mask_prefix),
+            mask_name_patterns.join("|"),
+            regex::escape(&self.masker.mask_suffix)
+        ))
+        .expect("failed to compile mask token regex");
+
+        let mut template_params: Vec<TemplateParameter> = Vec::new();
+        let mut template_regex = String::new();
+        let mut last = 0;
+
+        for caps in mask_token_regex.captures_iter(log_template) {
+            let mask_token = caps.get(0).unwrap();
+            let mask_name = if generated_token.is_match(&caps[1]) {
+                "*"
+            } else {
+                &caps[1]
+            };
+            let param_name = format!("p_{}", template_params.len());
+            let literal = &log_template[last..mask_token.start()];
+            last = mask_token.end();
+
+            if mask_name == VARIABLE_LENGTH_MASK {
+                // A variable-length wildcard may stand for no tokens at all, so it
+                // takes the whitespace on one side of it into its optional group.
+                if let Some(literal) = literal.strip_suffix(' ') {
+                    template_regex.push_str(&regex::escape(literal));
+                    template_regex.push_str(&format!(r"(?:\s+(?P<{}>.+?))?", param_name));
+                } else if log_template[last..].starts_with(' ') {
+                    template_regex.push_str(&regex::escape(literal));
+                    template_regex.push_str(&format!(r"(?:(?P<{}>.+?)\s+)?", param_name));
+                    last += 1;
+                } else {
+                    template_regex.push_str(&regex::escape(literal));
+                    template_regex.push_str(&format!("(?P<{}>.*?)", param_name));
                 }
-
-                template_regex = new;
+            } else {
+                template_regex.push_str(&regex::escape(literal));
+                template_regex.push_str(&create_capture_regex(mask_name, &param_name));
             }
+
+            template_params.push(TemplateParameter {
+                group_name: param_name,
+                mask_name: mask_name.to_string(),
+                template_offset: mask_token.start(),
+            });
         }
+        template_regex.push_str(&regex::escape(&log_template[last..]));
 
         let space_regex = Regex::new(r"\\ ").unwrap();
 
@@ -262,6 +594,101 @@ impl<'a> TemplateMiner<'a> {
 
         template_regex = format!("^{}$", template_regex);
 
-        (template_regex, param_map)
+        (template_regex, template_params)
+    }
+}
+
+fn drain_config(config: &TemplateMinerConfig) -> DrainConfig {
+    DrainConfig {
+        log_cluster_depth: config.drain_depth,
+        sim_th: config.drain_sim_th,
+        sim_th_by_length: config.drain_sim_th_by_length.clone(),
+        adaptive_sim_th: config.drain_adaptive_sim_th,
+        max_children: config.drain_max_children,
+        max_clusters: config.drain_max_clusters,
+        extra_delimiters: config.drain_extra_delimiters.clone(),
+        parametrize_numeric_tokens: config.parametrize_numeric_tokens,
+        routing_predicates: config.drain_routing_predicates.clone(),
+        max_length_difference: config.drain_max_length_difference,
+        template_split: config.drain_template_split.clone(),
+        cluster_merge: config.drain_cluster_merge.clone(),
+        value_stats: config.drain_value_stats.clone(),
+        template_history: config.drain_template_history,
+        token_prefix: config.mask_prefix.clone(),
+        token_suffix: config.mask_suffix.clone(),
+        token_template: config.token_template.clone(),
+    }
+}
+
+/// A capture group of the extraction regex and the mask token it stands for.
+struct TemplateParameter {
+    group_name: String,
+    mask_name: String,
+    template_offset: usize,
+}
+
+/// Finds the byte offset of each token in the text it was split from. A token the
+/// tokenizer rewrote, so that it is not found, is placed where the search stopped.
+fn locate_tokens(text: &str, tokens: &[String]) -> Vec<usize> {
+    let mut cursor = 0;
+    tokens
+        .iter()
+        .map(|token| match text[cursor..].find(token.as_str()) {
+            // The placeholder of an empty value is not in the text, but right after its key.
+            _ if token == tokenizer::EMPTY_VALUE && !text[cursor..].starts_with(token.as_str()) => {
+                cursor
+            }
+            Some(found) => {
+                let start = cursor + found;
+                cursor = start + token.len();
+                start
+            }
+            None => cursor,
+        })
+        .collect()
+}
+
+/// Where the empty value of a key is in the text, if `range` of the joined tokens is
+/// the placeholder the tokenizer gave it for value.
+fn empty_value_at(
+    range: Range<usize>,
+    joined_offsets: &[usize],
+    text: &str,
+    text_offsets: &[usize],
+    tokens: &[String],
+) -> Option<usize> {
+    let index = joined_offsets.binary_search(&range.start).ok()?;
+    let at = text_offsets[index];
+    (tokens[index] == tokenizer::EMPTY_VALUE
+        && range.len() == tokenizer::EMPTY_VALUE.len()
+        && !text[at..].starts_with(tokenizer::EMPTY_VALUE))
+    .then_some(at)
+}
+
+/// Maps a byte offset in the joined tokens back to the text they were split from.
+/// End offsets are attributed to the token they close rather than the next one.
+/// `None` if that token was rewritten by the tokenizer and so is not in the text.
+fn map_offset(
+    offset: usize,
+    is_end: bool,
+    joined_offsets: &[usize],
+    text: &str,
+    text_offsets: &[usize],
+    tokens: &[String],
+) -> Option<usize> {
+    let index = if is_end {
+        joined_offsets.iter().rposition(|&start| start < offset)
+    } else {
+        joined_offsets.iter().rposition(|&start| start <= offset)
+    };
+    let Some(index) = index else {
+        return Some(text_offsets.first().copied().unwrap_or(0));
+    };
+
+    let token = tokens[index].as_str();
+    if !text[text_offsets[index]..].starts_with(token) {
+        return None;
     }
+    let within = (offset - joined_offsets[index]).min(token.len());
+    Some(text_offsets[index] + within)
 }
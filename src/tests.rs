@@ -1,17 +1,36 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use crate::cluster::UpdateType;
     use crate::drain::Drain;
 
+    /// The drain numbering its next clusters from `first_id`, so that tests of the
+    /// process-wide cluster lookup don't collide with the clusters of other tests.
+    fn with_cluster_ids_from(drain: &Drain, first_id: usize) -> Drain {
+        use crate::drain::SerializableDrain;
+
+        let mut state = serde_json::to_value(SerializableDrain::from(drain)).unwrap();
+        state["clusters_counter"] = (first_id - 1).into();
+        Drain::from(serde_json::from_value::<SerializableDrain>(state).unwrap())
+    }
+
     #[test]
     fn test_drain_parsing() {
         let mut drain = Drain::new(&crate::drain::DrainConfig {
             log_cluster_depth: 4,
             sim_th: 0.4,
+            sim_th_by_length: vec![],
+            adaptive_sim_th: false,
             max_children: 100,
             max_clusters: None,
             extra_delimiters: vec![],
             parametrize_numeric_tokens: true,
+            routing_predicates: vec![],
+            max_length_difference: 0,
+            template_split: None,
+            cluster_merge: None,
+            value_stats: None,
+            template_history: 0,
             token_prefix: "<".to_string(),
             token_suffix: ">".to_string(),
             token_template: "TOKEN".to_string(),
@@ -48,10 +67,18 @@ mod tests {
         let mut drain = Drain::new(&crate::drain::DrainConfig {
             log_cluster_depth: 4,
             sim_th: 0.4,
+            sim_th_by_length: vec![],
+            adaptive_sim_th: false,
             max_children: 2,
             max_clusters: None,
             extra_delimiters: vec![],
             parametrize_numeric_tokens: true,
+            routing_predicates: vec![],
+            max_length_difference: 0,
+            template_split: None,
+            cluster_merge: None,
+            value_stats: None,
+            template_history: 0,
             token_prefix: "<".to_string(),
             token_suffix: ">".to_string(),
             token_template: "TOKEN".to_string(),
@@ -84,6 +111,7 @@ mod tests {
                 &MaskingInstructionConfig {
                     pattern: r"\d+".to_string(),
                     mask_with: "NUM".to_string(),
+                    ..Default::default()
                 },
             ))];
 
@@ -102,6 +130,7 @@ mod tests {
             MaskingInstruction::new(&MaskingInstructionConfig {
                 pattern: r"(?:[0-9a-f]{2,}:){3,}[0-9a-f]{2,}".to_string(),
                 mask_with: "ID".to_string(),
+                ..Default::default()
             }),
         )];
 
@@ -121,6 +150,7 @@ mod tests {
             MaskingInstruction::new(&MaskingInstructionConfig {
                 pattern: r"(\d{1,3}(\.\d{1,3}){3})".to_string(),
                 mask_with: "IP".to_string(),
+                ..Default::default()
             }),
         )];
 
@@ -140,6 +170,7 @@ mod tests {
             MaskingInstruction::new(&MaskingInstructionConfig {
                 pattern: r"([A-Za-z0-9-]+(\.[A-Za-z0-9-]+)+)".to_string(),
                 mask_with: "HOST".to_string(),
+                ..Default::default()
             }),
         )];
 
@@ -159,6 +190,7 @@ mod tests {
             MaskingInstruction::new(&MaskingInstructionConfig {
                 pattern: r"(([0-9a-f]{6,} ?){2,}([0-9a-f]{6,}))".to_string(),
                 mask_with: "SEQ".to_string(),
+                ..Default::default()
             }),
         )];
 
@@ -178,6 +210,7 @@ mod tests {
             MaskingInstruction::new(&MaskingInstructionConfig {
                 pattern: r"(([0-9A-F]{4} ?){3,}([0-9A-F]{4}))".to_string(),
                 mask_with: "SEQ".to_string(),
+                ..Default::default()
             }),
         )];
 
@@ -197,6 +230,7 @@ mod tests {
             MaskingInstruction::new(&MaskingInstructionConfig {
                 pattern: r"(0x[a-fA-F0-9]+)".to_string(),
                 mask_with: "HEX".to_string(),
+                ..Default::default()
             }),
         )];
 
@@ -216,6 +250,7 @@ mod tests {
             MaskingInstruction::new(&MaskingInstructionConfig {
                 pattern: r"([-+]?\d+)".to_string(),
                 mask_with: "NUM".to_string(),
+                ..Default::default()
             }),
         )];
 
@@ -235,6 +270,7 @@ mod tests {
             MaskingInstruction::new(&MaskingInstructionConfig {
                 pattern: r#"(executed cmd )(".+?")"#.to_string(),
                 mask_with: "CMD".to_string(),
+                ..Default::default()
             }),
         )];
 
@@ -254,6 +290,7 @@ mod tests {
             MaskingInstruction::new(&MaskingInstructionConfig {
                 pattern: r"'[^']*'".to_string(),
                 mask_with: "STR".to_string(),
+                ..Default::default()
             }),
         )];
 
@@ -273,6 +310,7 @@ mod tests {
             MaskingInstruction::new(&MaskingInstructionConfig {
                 pattern: r#""[^"]*""#.to_string(),
                 mask_with: "STR".to_string(),
+                ..Default::default()
             }),
         )];
 
@@ -281,4 +319,1896 @@ mod tests {
 
         assert_eq!(masked, "user <STR> logged in");
     }
+
+    #[test]
+    fn test_pseudonymize_masking() {
+        use crate::masking::{
+            AbstractMaskingInstruction, LogMasker, MaskingInstructionConfig, MaskingMode,
+            PseudonymVault, PseudonymizingMaskingInstruction,
+        };
+        use std::sync::Arc;
+
+        let vault = Arc::new(PseudonymVault::new());
+        let instructions: Vec<Box<dyn AbstractMaskingInstruction>> =
+            vec![Box::new(PseudonymizingMaskingInstruction::new(
+                &MaskingInstructionConfig {
+                    pattern: r"[\w.+-]+@[\w-]+(\.[\w-]+)+".to_string(),
+                    mask_with: "EMAIL".to_string(),
+                    mode: MaskingMode::Pseudonymize,
+                    ..Default::default()
+                },
+                b"Jefe",
+                Some(vault.clone()),
+            ))];
+
+        let masker = LogMasker::new(instructions, "<", ">");
+        let masked1 = masker.mask("login alice@example.com ok");
+        let masked2 = masker.mask("logout alice@example.com");
+        let masked3 = masker.mask("login bob@example.com ok");
+
+        let pseudonym = masked1.split_whitespace().nth(1).unwrap().to_string();
+        assert!(pseudonym.starts_with("EMAIL_"));
+        assert_eq!(pseudonym.len(), "EMAIL_".len() + 16);
+        assert_eq!(masked2, format!("logout {}", pseudonym));
+        assert_ne!(masked3, masked1);
+
+        assert_eq!(vault.len(), 2);
+        assert_eq!(vault.reveal(&pseudonym).unwrap(), "alice@example.com");
+        assert_eq!(vault.detokenize(&masked1), "login alice@example.com ok");
+
+        // A bounded vault forgets the least recently seen pseudonyms.
+        let vault = Arc::new(PseudonymVault::with_capacity(1));
+        let instructions: Vec<Box<dyn AbstractMaskingInstruction>> =
+            vec![Box::new(PseudonymizingMaskingInstruction::new(
+                &MaskingInstructionConfig {
+                    pattern: r"[\w.+-]+@[\w-]+(\.[\w-]+)+".to_string(),
+                    mask_with: "EMAIL".to_string(),
+                    mode: MaskingMode::Pseudonymize,
+                    ..Default::default()
+                },
+                b"Jefe",
+                Some(vault.clone()),
+            ))];
+        let masker = LogMasker::new(instructions, "<", ">");
+        masker.mask("login alice@example.com ok");
+        let masked = masker.mask("login bob@example.com ok");
+        assert_eq!(vault.len(), 1);
+        assert_eq!(vault.reveal(&pseudonym), None);
+        assert_eq!(vault.detokenize(&masked), "login bob@example.com ok");
+    }
+
+    #[test]
+    fn test_pseudonymization_key_not_serialized() {
+        use crate::config::TemplateMinerConfig;
+
+        let config = TemplateMinerConfig {
+            pseudonymization_key: "s3cret".to_string(),
+            ..Default::default()
+        };
+        let serialized = serde_json::to_string(&config).unwrap();
+        assert!(!serialized.contains("s3cret"));
+        assert!(!serialized.contains("pseudonymization_key"));
+    }
+
+    #[test]
+    fn test_pseudonym_hmac() {
+        use crate::masking::{MaskingInstructionConfig, PseudonymizingMaskingInstruction};
+
+        // RFC 4231 test case 2
+        let mi = PseudonymizingMaskingInstruction::new(
+            &MaskingInstructionConfig {
+                pattern: ".+".to_string(),
+                mask_with: "P".to_string(),
+                ..Default::default()
+            },
+            b"Jefe",
+            None,
+        );
+        assert_eq!(
+            mi.pseudonym("what do ya want for nothing?"),
+            "P_5bdcc146bf60754e"
+        );
+
+        let config = MaskingInstructionConfig {
+            pattern: ".+".to_string(),
+            mask_with: "P".to_string(),
+            ..Default::default()
+        };
+        // RFC 4231 test case 1
+        let mi = PseudonymizingMaskingInstruction::new(&config, &[0x0b; 20], None);
+        assert_eq!(mi.pseudonym("Hi There"), "P_b0344c61d8db3853");
+        // RFC 4231 test case 6, a key longer than the block size
+        let mi = PseudonymizingMaskingInstruction::new(&config, &[0xaa; 131], None);
+        assert_eq!(
+            mi.pseudonym("Test Using Larger Than Block-Size Key - Hash Key First"),
+            "P_60e431591ee0b67f"
+        );
+    }
+
+    #[test]
+    fn test_masking_examples() {
+        use crate::masking::{
+            AbstractMaskingInstruction, LogMasker, MaskingExample, MaskingInstruction,
+            MaskingInstructionConfig, PseudonymVault, PseudonymizingMaskingInstruction,
+        };
+        use std::sync::Arc;
+
+        let config = MaskingInstructionConfig {
+            pattern: r"(\d{1,3}(\.\d{1,3}){3})".to_string(),
+            mask_with: "IP".to_string(),
+            examples: vec![MaskingExample {
+                input: "connect 10.1.1.0 success".to_string(),
+                expected: "connect <IP> success".to_string(),
+            }],
+            counter_examples: vec!["version 1.2 released".to_string()],
+            ..Default::default()
+        };
+        let instructions: Vec<Box<dyn AbstractMaskingInstruction>> =
+            vec![Box::new(MaskingInstruction::new(&config))];
+        assert!(LogMasker::try_new(instructions, "<", ">").is_ok());
+
+        let mut bad_example = config.clone();
+        bad_example.examples[0].expected = "connect <HOST> success".to_string();
+        let instructions: Vec<Box<dyn AbstractMaskingInstruction>> =
+            vec![Box::new(MaskingInstruction::new(&bad_example))];
+        let err = LogMasker::try_new(instructions, "<", ">").err().unwrap();
+        assert!(err.to_string().contains("masking instruction IP"));
+        assert!(err.to_string().contains("example 1"));
+
+        let mut bad_counter_example = config.clone();
+        bad_counter_example.counter_examples = vec!["from 10.0.0.1".to_string()];
+        let instructions: Vec<Box<dyn AbstractMaskingInstruction>> =
+            vec![Box::new(MaskingInstruction::new(&bad_counter_example))];
+        let err = LogMasker::try_new(instructions, "<", ">").err().unwrap();
+        assert!(err.to_string().contains("counter example 1"));
+
+        // Validating examples leaves the pseudonym vault untouched.
+        let pseudonym =
+            PseudonymizingMaskingInstruction::new(&config, b"key", None).pseudonym("10.1.1.0");
+        let mut pseudonymize = config.clone();
+        pseudonymize.examples[0].expected = format!("connect {} success", pseudonym);
+        let vault = Arc::new(PseudonymVault::new());
+        let instructions: Vec<Box<dyn AbstractMaskingInstruction>> = vec![Box::new(
+            PseudonymizingMaskingInstruction::new(&pseudonymize, b"key", Some(vault.clone())),
+        )];
+        let masker = LogMasker::try_new(instructions, "<", ">").unwrap();
+        assert!(vault.is_empty());
+        masker.mask("connect 10.1.1.0 success");
+        assert_eq!(vault.len(), 1);
+    }
+
+    #[test]
+    fn test_scoped_masking() {
+        use crate::masking::{
+            AbstractMaskingInstruction, LogMasker, MaskingInstruction, MaskingInstructionConfig,
+        };
+
+        let host = |guard_pattern: Option<&str>,
+                    after_prefix: Option<&str>,
+                    token_start: Option<usize>,
+                    token_end: Option<usize>| {
+            let instructions: Vec<Box<dyn AbstractMaskingInstruction>> = vec![Box::new(
+                MaskingInstruction::new(&MaskingInstructionConfig {
+                    pattern: r"([A-Za-z0-9-]+(\.[A-Za-z0-9-]+)+)".to_string(),
+                    mask_with: "HOST".to_string(),
+                    guard_pattern: guard_pattern.map(str::to_string),
+                    after_prefix: after_prefix.map(str::to_string),
+                    token_start,
+                    token_end,
+                    ..Default::default()
+                }),
+            )];
+            LogMasker::new(instructions, "<", ">")
+        };
+
+        let masker = host(Some(r"^connect "), None, None, None);
+        assert_eq!(masker.mask("connect server.example.com"), "connect <HOST>");
+        assert_eq!(masker.mask("version 1.2.3"), "version 1.2.3");
+
+        let masker = host(None, Some("host="), None, None);
+        assert_eq!(
+            masker.mask("v1.2 host=server.example.com"),
+            "v1.2 host=<HOST>"
+        );
+        assert_eq!(masker.mask("v1.2 done"), "v1.2 done");
+
+        let masker = host(None, None, Some(1), Some(2));
+        assert_eq!(
+            masker.mask("v1.2 server.example.com  file.txt"),
+            "v1.2 <HOST>  file.txt"
+        );
+        assert_eq!(masker.mask("v1.2"), "v1.2");
+    }
+
+    #[test]
+    fn test_transform_instructions() {
+        use crate::masking::{
+            AbstractMaskingInstruction, LogMasker, MaskingInstruction, MaskingInstructionConfig,
+            MaskingMode, TransformInstruction, TransformKind,
+        };
+
+        let transform = |pattern: &str, transform: TransformKind, replace_with: &str| {
+            Box::new(TransformInstruction::new(&MaskingInstructionConfig {
+                pattern: pattern.to_string(),
+                mode: MaskingMode::Transform,
+                transform,
+                replace_with: replace_with.to_string(),
+                ..Default::default()
+            })) as Box<dyn AbstractMaskingInstruction>
+        };
+
+        let instructions: Vec<Box<dyn AbstractMaskingInstruction>> = vec![
+            transform(r"\x1b\[[0-9;]*m", TransformKind::Replace, ""),
+            transform(r" {2,}", TransformKind::Replace, " "),
+            transform(r"\bERR\b", TransformKind::Replace, "ERROR"),
+            transform(r"\b0x[0-9a-fA-F]+\b", TransformKind::Lowercase, ""),
+            Box::new(MaskingInstruction::new(&MaskingInstructionConfig {
+                pattern: r"\b\d+\b".to_string(),
+                mask_with: "NUM".to_string(),
+                ..Default::default()
+            })),
+        ];
+
+        let masker = LogMasker::new(instructions, "<", ">");
+        assert_eq!(
+            masker.mask("\x1b[31mERR\x1b[0m  addr 0xDEADBEEF   code 42"),
+            "ERROR addr 0xdeadbeef code <NUM>"
+        );
+        assert_eq!(masker.mask_names(), vec!["NUM".to_string()]);
+    }
+
+    #[test]
+    fn test_grok_masking() {
+        use crate::grok::GrokPatterns;
+        use crate::masking::{
+            AbstractMaskingInstruction, LogMasker, MaskingInstruction, MaskingInstructionConfig,
+        };
+
+        let mut grok = GrokPatterns::new();
+        grok.load_definitions(
+            "# custom patterns\nSESSIONID sess-%{BASE16NUM}\nIPPORT %{IPV4}:%{POSINT}\n",
+        );
+
+        let configs = grok
+            .expand_masking_instructions(&[
+                MaskingInstructionConfig {
+                    grok_pattern: Some("%{SESSIONID:session}".to_string()),
+                    ..Default::default()
+                },
+                MaskingInstructionConfig {
+                    grok_pattern: Some("%{IPPORT}".to_string()),
+                    ..Default::default()
+                },
+                MaskingInstructionConfig {
+                    grok_pattern: Some("%{IPV4:ip}".to_string()),
+                    ..Default::default()
+                },
+                MaskingInstructionConfig {
+                    pattern: r"\d+".to_string(),
+                    mask_with: "NUM".to_string(),
+                    ..Default::default()
+                },
+            ])
+            .unwrap();
+
+        let names: Vec<&str> = configs.iter().map(|c| c.mask_with.as_str()).collect();
+        assert_eq!(names, vec!["session", "IPPORT", "ip", "NUM"]);
+
+        let instructions: Vec<Box<dyn AbstractMaskingInstruction>> = configs
+            .iter()
+            .map(|c| Box::new(MaskingInstruction::new(c)) as Box<dyn AbstractMaskingInstruction>)
+            .collect();
+        let masker = LogMasker::new(instructions, "<", ">");
+        assert_eq!(
+            masker.mask("sess-3fa9 from 10.0.0.1:22 via 10.0.0.2 in 5 ms"),
+            "<session> from <IPPORT> via <ip> in <NUM> ms"
+        );
+
+        assert!(grok.expand("%{NOPE:x}").is_err());
+        assert!(
+            grok.expand_masking_instructions(&[MaskingInstructionConfig {
+                grok_pattern: Some("%{IPV4:src} %{IPV4:dst}".to_string()),
+                ..Default::default()
+            }])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_tokenizer_delimiters() {
+        use crate::config::TemplateMinerConfig;
+        use crate::template_miner::TemplateMiner;
+        use crate::tokenizer::TokenizerConfig;
+
+        let config = TemplateMinerConfig {
+            drain_extra_delimiters: vec!["|".to_string()],
+            ..Default::default()
+        };
+        let miner = TemplateMiner::new(&config, None);
+        assert_eq!(
+            miner.drain.get_content_as_tokens("user|bob  done"),
+            vec!["user", "bob", "done"]
+        );
+        let params = miner
+            .extract_parameters("user <*> done", "user|bob done", false)
+            .unwrap();
+        assert_eq!(params.len(), 1);
+        assert_eq!(params[0].value, "bob");
+
+        let config = TemplateMinerConfig {
+            drain_extra_delimiters: vec!["[|,]".to_string()],
+            tokenizer: TokenizerConfig {
+                kind: "regex_delimiter".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let miner = TemplateMiner::new(&config, None);
+        assert_eq!(
+            miner.drain.get_content_as_tokens("user|bob,alice done"),
+            vec!["user", "bob", "alice", "done"]
+        );
+        let params = miner
+            .extract_parameters("user <*> alice done", "user|bob,alice done", false)
+            .unwrap();
+        assert_eq!(params[0].value, "bob");
+    }
+
+    #[test]
+    fn test_custom_tokenizer() {
+        use crate::config::TemplateMinerConfig;
+        use crate::template_miner::TemplateMiner;
+        use crate::tokenizer::{self, Tokenizer, TokenizerConfig};
+        use std::sync::Arc;
+
+        #[derive(Debug)]
+        struct CommaTokenizer;
+
+        impl Tokenizer for CommaTokenizer {
+            fn tokenize(&self, content: &str) -> Vec<String> {
+                content.split(',').map(|s| s.trim().to_string()).collect()
+            }
+        }
+
+        tokenizer::register_tokenizer("comma", |_, _| Ok(Arc::new(CommaTokenizer)));
+
+        let config = TemplateMinerConfig {
+            tokenizer: TokenizerConfig {
+                kind: "comma".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let miner = TemplateMiner::new(&config, None);
+        assert_eq!(
+            miner.drain.get_content_as_tokens("a b, c"),
+            vec!["a b", "c"]
+        );
+
+        let unknown = TokenizerConfig {
+            kind: "unknown".to_string(),
+            ..Default::default()
+        };
+        assert!(tokenizer::create_tokenizer(&unknown, &[]).is_err());
+    }
+
+    #[test]
+    fn test_quote_aware_tokenizer() {
+        use crate::tokenizer::{self, TokenizerConfig};
+
+        let config = TokenizerConfig {
+            kind: "quote_aware".to_string(),
+            ..Default::default()
+        };
+        let tokenizer = tokenizer::create_tokenizer(&config, &["|".to_string()]).unwrap();
+
+        assert_eq!(
+            tokenizer.tokenize(r#"executed cmd "ls -la /tmp" ok"#),
+            vec!["executed", "cmd", r#""ls -la /tmp""#, "ok"]
+        );
+        assert_eq!(
+            tokenizer.tokenize("call args=[a, [b, c]] f(x, y)|done"),
+            vec!["call", "args=[a, [b, c]]", "f(x, y)", "done"]
+        );
+        assert_eq!(
+            tokenizer.tokenize(r#"don't split msg='it is' "a \"b\" c""#),
+            vec!["don't", "split", "msg='it is'", r#""a \"b\" c""#]
+        );
+        // Groups left open at the end of the line are split like any other text.
+        assert_eq!(
+            tokenizer.tokenize("unterminated [a b"),
+            vec!["unterminated", "[a", "b"]
+        );
+        assert_eq!(
+            tokenizer.tokenize(r#"stray (x "a b" [c d] 'e f"#),
+            vec!["stray", "(x", r#""a b""#, "[c d]", "'e", "f"]
+        );
+        // A quote is closed after an even run of backslashes, which escape each other.
+        assert_eq!(
+            tokenizer.tokenize(r#"path "a\\" next "b\\\" c" end"#),
+            vec!["path", r#""a\\""#, "next", r#""b\\\" c""#, "end"]
+        );
+    }
+
+    #[test]
+    fn test_quote_aware_tokenizer_long_unclosed_line() {
+        use crate::tokenizer::{self, TokenizerConfig};
+        use std::time::{Duration, Instant};
+
+        let config = TokenizerConfig {
+            kind: "quote_aware".to_string(),
+            ..Default::default()
+        };
+        let tokenizer = tokenizer::create_tokenizer(&config, &[]).unwrap();
+
+        // Every opening is unclosed, which must not take a scan per opening.
+        let line = format!("{} {}", "(".repeat(20_000), "[a ".repeat(5_000));
+        let started = Instant::now();
+        let tokens = tokenizer.tokenize(&line);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(tokens.len(), 1 + 5_000);
+        assert_eq!(tokens[0], "(".repeat(20_000));
+        assert!(tokens[1..].iter().all(|t| t == "[a"));
+    }
+
+    #[test]
+    fn test_key_value_tokenizer() {
+        use crate::config::TemplateMinerConfig;
+        use crate::template_miner::TemplateMiner;
+        use crate::tokenizer::{self, TokenizerConfig};
+        use std::ops::Range;
+
+        let config = TemplateMinerConfig {
+            tokenizer: TokenizerConfig {
+                kind: "key_value".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let miner = TemplateMiner::new(&config, None);
+
+        let tokens = miner
+            .drain
+            .get_content_as_tokens(r#"user=alice status=200 msg="a b" url=http://x/?q=1"#);
+        assert_eq!(
+            tokens,
+            vec![
+                "user=",
+                "alice",
+                "status=",
+                "200",
+                "msg=",
+                r#""a b""#,
+                "url=",
+                "http://x/?q=1"
+            ]
+        );
+        assert_eq!(
+            tokenizer::join_tokens(&tokens, Some("=")),
+            r#"user=alice status=200 msg="a b" url=http://x/?q=1"#
+        );
+
+        let params = miner
+            .extract_parameters(
+                "user=<*> status=<*> latency=12ms",
+                "user=alice status=200 latency=12ms",
+                false,
+            )
+            .unwrap();
+        let mut named: Vec<(String, String)> = params
+            .into_iter()
+            .map(|p| (p.key.unwrap(), p.value))
+            .collect();
+        named.sort();
+        assert_eq!(
+            named,
+            vec![
+                ("status".to_string(), "200".to_string()),
+                ("user".to_string(), "alice".to_string())
+            ]
+        );
+
+        // A value ending with the separator is not a key, so it is not glued to the
+        // next key.
+        let tokens = miner.drain.get_content_as_tokens("token=YWJj= status=200");
+        assert_eq!(tokens, vec!["token=", "YWJj=", "status=", "200"]);
+        assert_eq!(
+            tokenizer::join_tokens(&tokens, Some("=")),
+            "token=YWJj= status=200"
+        );
+        let params = miner
+            .extract_parameters(
+                "token=<TOKEN1> status=<TOKEN2>",
+                "token=YWJj= status=200",
+                false,
+            )
+            .unwrap();
+        let named: Vec<(Option<String>, String)> =
+            params.into_iter().map(|p| (p.key, p.value)).collect();
+        assert_eq!(
+            named,
+            vec![
+                (Some("token".to_string()), "YWJj=".to_string()),
+                (Some("status".to_string()), "200".to_string())
+            ]
+        );
+
+        // Empty values get a value token of their own, which survives rendering.
+        let tokens = miner.drain.get_content_as_tokens("user= status=200");
+        assert_eq!(tokens, vec!["user=", r#""""#, "status=", "200"]);
+        let rendered = tokenizer::join_tokens(&tokens, Some("="));
+        assert_eq!(rendered, r#"user="" status=200"#);
+        assert_eq!(miner.drain.get_content_as_tokens(&rendered), tokens);
+        let params = miner
+            .extract_parameters("user=<*> status=<*>", "user= status=200", false)
+            .unwrap();
+        let values: Vec<String> = params.into_iter().map(|p| p.value).collect();
+        assert_eq!(values, vec!["", "200"]);
+
+        // The placeholder is reported as the empty value at the end of its key, and
+        // an actual empty quoted value as itself.
+        let params = miner
+            .extract_parameters("key=<*> next=<*>", "key= next=1", false)
+            .unwrap();
+        let values: Vec<(String, Option<Range<usize>>, Option<String>)> = params
+            .into_iter()
+            .map(|p| (p.value, p.span, p.original))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                (String::new(), Some(4..4), None),
+                ("1".to_string(), Some(10..11), None)
+            ]
+        );
+        let params = miner
+            .extract_parameters(r#"key=<*> next=<*>"#, r#"key="" next="""#, false)
+            .unwrap();
+        let spans: Vec<(String, Option<Range<usize>>)> =
+            params.into_iter().map(|p| (p.value, p.span)).collect();
+        assert_eq!(
+            spans,
+            vec![
+                (r#""""#.to_string(), Some(4..6)),
+                (r#""""#.to_string(), Some(12..14))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_drain_snapshot_roundtrip() {
+        use crate::cluster::SearchStrategy;
+        use crate::drain::SerializableDrain;
+
+        let mut drain = Drain::new(&crate::drain::DrainConfig {
+            log_cluster_depth: 4,
+            sim_th: 0.4,
+            sim_th_by_length: vec![],
+            adaptive_sim_th: false,
+            max_children: 100,
+            max_clusters: None,
+            extra_delimiters: vec![],
+            parametrize_numeric_tokens: true,
+            routing_predicates: vec![],
+            max_length_difference: 0,
+            template_split: None,
+            cluster_merge: None,
+            value_stats: None,
+            template_history: 0,
+            token_prefix: "<".to_string(),
+            token_suffix: ">".to_string(),
+            token_template: "TOKEN".to_string(),
+        });
+        drain.add_log_message("session opened for user alice");
+        drain.add_log_message("session opened for user bob");
+
+        let state = serde_json::to_vec(&SerializableDrain::from(&drain)).unwrap();
+        let ser_drain: SerializableDrain = serde_json::from_slice(&state).unwrap();
+        let mut loaded = Drain::from(ser_drain);
+
+        let cluster = loaded
+            .match_cluster("session opened for user <TOKEN1>", SearchStrategy::Fast)
+            .unwrap();
+        assert_eq!(
+            cluster.lock().unwrap().get_template(),
+            "session opened for user <TOKEN1>"
+        );
+        assert!(
+            loaded
+                .match_cluster("session opened for user carol", SearchStrategy::Fast)
+                .is_some()
+        );
+        assert!(
+            loaded
+                .match_cluster("session closed for user carol", SearchStrategy::Full)
+                .is_none()
+        );
+
+        let (cluster, update_type) = loaded.add_log_message("session opened for user carol");
+        assert_eq!(update_type, UpdateType::None);
+        assert_eq!(cluster.unwrap().lock().unwrap().size, 3);
+    }
+
+    #[test]
+    fn test_unicode_tokenizer() {
+        use crate::tokenizer::{self, TokenizerConfig};
+
+        let config = TokenizerConfig {
+            kind: "unicode".to_string(),
+            ..Default::default()
+        };
+        let tokenizer = tokenizer::create_tokenizer(&config, &[]).unwrap();
+
+        assert_eq!(
+            tokenizer.tokenize("用户alice登录成功 from 10.0.0.1"),
+            vec![
+                "用", "户", "alice", "登", "录", "成", "功", "from", "10.0.0.1"
+            ]
+        );
+        assert_eq!(
+            tokenizer.tokenize("ファイルを開けません：２０件"),
+            vec![
+                "ファイル",
+                "を",
+                "開",
+                "け",
+                "ま",
+                "せ",
+                "ん",
+                "：",
+                "２０",
+                "件"
+            ]
+        );
+
+        assert!(tokenizer.has_numbers("２０"));
+        assert!(tokenizer.has_numbers("٣"));
+        assert!(!tokenizer.has_numbers("件"));
+
+        let whitespace = tokenizer::create_tokenizer(&TokenizerConfig::default(), &[]).unwrap();
+        assert!(!whitespace.has_numbers("２０"));
+    }
+
+    #[test]
+    fn test_log_format() {
+        use crate::config::TemplateMinerConfig;
+        use crate::log_format::LogFormat;
+        use crate::template_miner::TemplateMiner;
+
+        let format = LogFormat::new("<Date> <Time> <Pid> <Level> <Component>: <Content>").unwrap();
+        let parsed = format
+            .parse("081109 203615 148 INFO  dfs.DataNode$PacketResponder: Received block of size 91178")
+            .unwrap();
+        assert_eq!(parsed.content, "Received block of size 91178");
+        assert_eq!(parsed.metadata["Date"], "081109");
+        assert_eq!(parsed.metadata["Pid"], "148");
+        assert_eq!(parsed.metadata["Level"], "INFO");
+        assert_eq!(parsed.metadata["Component"], "dfs.DataNode$PacketResponder");
+        assert!(!parsed.metadata.contains_key("Content"));
+        assert!(format.parse("no header here").is_none());
+        assert!(LogFormat::new("<Date> <Time>").is_err());
+
+        let config = TemplateMinerConfig {
+            log_format: Some("<Month> <Day> <Time> <Component> sshd[<Pid>]: <Content>".to_string()),
+            ..Default::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+        miner.add_log_line("Dec 10 06:55:46 LabSZ sshd[24200]: Failed password for root");
+        let mined =
+            miner.add_log_line("Dec 10 07:01:02 LabSZ sshd[24311]: Failed password for admin");
+        assert_eq!(
+            mined.cluster.unwrap().lock().unwrap().get_template(),
+            "Failed password for <TOKEN1>"
+        );
+        assert_eq!(mined.metadata["Pid"], "24311");
+        assert_eq!(mined.metadata["Time"], "07:01:02");
+
+        let mined = miner.add_log_line("kernel panic");
+        assert!(mined.metadata.is_empty());
+        assert_eq!(
+            mined.cluster.unwrap().lock().unwrap().get_template(),
+            "kernel panic"
+        );
+
+        // The example config leaves the format opt-in, mining lines whole.
+        let demo: toml::Table = toml::from_str(include_str!("../examples/drain3.toml")).unwrap();
+        let config: TemplateMinerConfig = demo["miner_config"].clone().try_into().unwrap();
+        assert!(config.log_format.is_none());
+        let line = "Dec 10 06:55:46 LabSZ sshd[24200]: Failed password for root";
+        let miner = TemplateMiner::new(&config, None);
+        assert_eq!(miner.parse_log_line(line).content, line);
+    }
+
+    #[test]
+    fn test_record_assembler() {
+        use crate::config::TemplateMinerConfig;
+        use crate::record_assembler::{MultilineConfig, RecordAssembler};
+        use crate::template_miner::TemplateMiner;
+
+        let mut assembler = RecordAssembler::new(&MultilineConfig::default()).unwrap();
+        assert!(
+            assembler
+                .push("Exception in thread main java.lang.IllegalStateException: boom")
+                .is_none()
+        );
+        assert!(
+            assembler
+                .push("    at com.example.App.run(App.java:42)")
+                .is_none()
+        );
+        assert!(
+            assembler
+                .push("Caused by: java.io.IOException: closed")
+                .is_none()
+        );
+        assert!(assembler.push("    ... 3 more").is_none());
+        let record = assembler.push("next event").unwrap();
+        assert_eq!(
+            record.first_line,
+            "Exception in thread main java.lang.IllegalStateException: boom"
+        );
+        assert_eq!(record.payload.len(), 3);
+        assert_eq!(assembler.flush().unwrap().first_line, "next event");
+        assert!(assembler.flush().is_none());
+
+        let mut assembler = RecordAssembler::new(&MultilineConfig {
+            start_patterns: vec![r"^\d{4}-\d{2}-\d{2} ".to_string()],
+            continuation_patterns: vec![],
+            max_lines: 3,
+            flush_timeout_ms: 0,
+        })
+        .unwrap();
+        assert!(assembler.push("2024-01-01 panic: oops").is_none());
+        assert!(assembler.push("goroutine 1 [running]:").is_none());
+        let record = assembler.push("main.main()").unwrap();
+        assert_eq!(
+            record.payload,
+            vec!["goroutine 1 [running]:", "main.main()"]
+        );
+        assert!(assembler.push("2024-01-01 started").is_none());
+        assert_eq!(assembler.poll().unwrap().first_line, "2024-01-01 started");
+
+        let config = TemplateMinerConfig {
+            multiline: Some(MultilineConfig::default()),
+            ..Default::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+        let traceback = [
+            "Traceback (most recent call last):",
+            "  File \"app.py\", line 3, in <module>",
+            "    main()",
+        ];
+        for line in traceback {
+            assert!(miner.push_log_line(line).is_none());
+        }
+        let mined = miner.flush_log_record().unwrap();
+        assert_eq!(
+            mined.cluster.unwrap().lock().unwrap().get_template(),
+            "Traceback (most recent call last):"
+        );
+        assert_eq!(mined.payload.len(), 2);
+        assert_eq!(mined.update_type, crate::cluster::UpdateType::Created);
+        assert!(miner.poll_log_record().is_none());
+    }
+
+    #[test]
+    fn test_json_input() {
+        use crate::cluster::SearchStrategy;
+        use crate::config::TemplateMinerConfig;
+        use crate::drain::{Drain, SerializableDrain};
+        use crate::json_input::JsonInputConfig;
+        use crate::template_miner::TemplateMiner;
+
+        let config = TemplateMinerConfig {
+            json_input: JsonInputConfig {
+                message_field: "/log/msg".to_string(),
+                metadata_fields: vec!["level".to_string(), "/trace/id".to_string()],
+                partition_field: Some("service".to_string()),
+            },
+            ..Default::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+
+        let mined = miner
+            .add_json_log_line(
+                r#"{"log": {"msg": "user alice logged in"}, "level": "INFO", "service": "auth", "trace": {"id": 42}}"#,
+            )
+            .unwrap();
+        assert_eq!(mined.metadata["level"], "INFO");
+        assert_eq!(mined.metadata["/trace/id"], "42");
+        let auth_id = mined.cluster.unwrap().lock().unwrap().cluster_id;
+
+        let mined = miner
+            .add_json_log_line(r#"{"log": {"msg": "user bob logged in"}, "service": "auth"}"#)
+            .unwrap();
+        let cluster = mined.cluster.unwrap();
+        assert_eq!(cluster.lock().unwrap().cluster_id, auth_id);
+        assert_eq!(
+            cluster.lock().unwrap().get_template(),
+            "user <TOKEN1> logged in"
+        );
+        assert_eq!(cluster.lock().unwrap().partition.as_deref(), Some("auth"));
+        assert!(!mined.metadata.contains_key("level"));
+
+        let mined = miner
+            .add_json_log_line(r#"{"log": {"msg": "user carol logged in"}, "service": "billing"}"#)
+            .unwrap();
+        assert_ne!(mined.cluster.unwrap().lock().unwrap().cluster_id, auth_id);
+
+        assert!(miner.add_json_log_line(r#"{"service": "auth"}"#).is_err());
+        assert!(miner.add_json_log_line("not json").is_err());
+
+        assert!(
+            miner
+                .match_json_cluster(
+                    r#"{"log": {"msg": "user dave logged in"}, "service": "auth"}"#,
+                    SearchStrategy::Fallback
+                )
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            miner
+                .match_json_cluster(
+                    r#"{"log": {"msg": "user dave logged in"}, "service": "web"}"#,
+                    SearchStrategy::Fallback
+                )
+                .unwrap()
+                .is_none()
+        );
+
+        let state = serde_json::to_vec(&SerializableDrain::from(&miner.drain)).unwrap();
+        let restored = Drain::from(serde_json::from_slice::<SerializableDrain>(&state).unwrap());
+        let mut partitions = restored.partitions();
+        partitions.sort();
+        assert_eq!(partitions, vec!["auth", "billing"]);
+        assert!(
+            restored
+                .match_partitioned_cluster(
+                    "user <TOKEN1> logged in",
+                    Some("auth"),
+                    SearchStrategy::Fast
+                )
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_variable_length_templates() {
+        use crate::cluster::SearchStrategy;
+        use crate::config::TemplateMinerConfig;
+        use crate::drain::{Drain, SerializableDrain};
+        use crate::template_miner::TemplateMiner;
+
+        let config = TemplateMinerConfig {
+            drain_max_length_difference: 3,
+            ..Default::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+
+        let (cluster, _) = miner.add_log_message("failed to open file a.txt");
+        let cluster_id = cluster.unwrap().lock().unwrap().cluster_id;
+
+        let (cluster, update_type) =
+            miner.add_log_message("failed to open file b.txt: permission denied");
+        let cluster = cluster.unwrap();
+        assert_eq!(update_type, UpdateType::Updated);
+        assert_eq!(cluster.lock().unwrap().cluster_id, cluster_id);
+        assert_eq!(
+            cluster.lock().unwrap().get_template(),
+            "failed to open file <TOKEN1> <...>"
+        );
+
+        let (cluster, update_type) = miner.add_log_message("failed to open file c.txt");
+        assert_eq!(update_type, UpdateType::None);
+        assert_eq!(cluster.unwrap().lock().unwrap().cluster_id, cluster_id);
+        let (cluster, update_type) =
+            miner.add_log_message("failed to open file d.txt: disk quota exceeded");
+        assert_eq!(update_type, UpdateType::None);
+        assert_eq!(cluster.unwrap().lock().unwrap().size, 4);
+
+        let (cluster, _) = miner.add_log_message("connection reset by peer");
+        assert_ne!(cluster.unwrap().lock().unwrap().cluster_id, cluster_id);
+
+        let matched = miner.match_cluster(
+            "failed to open file e.txt: no such file",
+            SearchStrategy::Fallback,
+        );
+        assert_eq!(matched.unwrap().lock().unwrap().cluster_id, cluster_id);
+        assert!(
+            miner
+                .match_cluster("failed to read file e.txt", SearchStrategy::Fallback)
+                .is_none()
+        );
+
+        let mut params = miner
+            .extract_parameters(
+                "failed to open file <*> <...>",
+                "failed to open file e.txt: permission denied",
+                false,
+            )
+            .unwrap();
+        params.sort_by(|a, b| a.mask_name.cmp(&b.mask_name));
+        assert_eq!(params[0].mask_name, "*");
+        assert_eq!(params[0].value, "e.txt:");
+        assert_eq!(params[1].mask_name, "...");
+        assert_eq!(params[1].value, "permission denied");
+
+        let params = miner
+            .extract_parameters(
+                "failed to open file <*> <...>",
+                "failed to open file e.txt",
+                false,
+            )
+            .unwrap();
+        assert_eq!(params.len(), 1);
+        assert_eq!(params[0].value, "e.txt");
+
+        let state = serde_json::to_vec(&SerializableDrain::from(&miner.drain)).unwrap();
+        let restored = Drain::from(serde_json::from_slice::<SerializableDrain>(&state).unwrap());
+        let matched = restored.match_cluster(
+            "failed to open file f.txt: is a directory",
+            SearchStrategy::Fast,
+        );
+        assert_eq!(matched.unwrap().lock().unwrap().cluster_id, cluster_id);
+    }
+
+    #[test]
+    fn test_parameter_spans() {
+        use crate::config::TemplateMinerConfig;
+        use crate::masking::MaskingInstructionConfig;
+        use crate::template_miner::TemplateMiner;
+
+        let config = TemplateMinerConfig {
+            drain_extra_delimiters: vec!["|".to_string()],
+            masking_instructions: vec![MaskingInstructionConfig {
+                pattern: r"\d+\.\d+\.\d+\.\d+".to_string(),
+                mask_with: "IP".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let miner = TemplateMiner::new(&config, None);
+
+        let message = "user  alice|logged in from 10.0.0.1";
+        let params = miner
+            .extract_parameters("user <*> logged in from <IP>", message, true)
+            .unwrap();
+        assert_eq!(params.len(), 2);
+        assert_eq!(params[0].mask_name, "*");
+        assert_eq!(params[0].value, "alice");
+        assert_eq!(params[0].span, Some(6..11));
+        assert_eq!(params[0].original, None);
+        assert_eq!(params[0].position, 1);
+        assert_eq!(params[1].mask_name, "IP");
+        assert_eq!(&message[params[1].span.clone().unwrap()], "10.0.0.1");
+        assert_eq!(params[1].original, None);
+        assert_eq!(params[1].position, 5);
+
+        let params = miner
+            .extract_parameters("user <*> from <IP>", message, false)
+            .unwrap();
+        assert_eq!(params[0].value, "alice logged in");
+        assert_eq!(params[0].original.as_deref(), Some("alice|logged in"));
+        assert_eq!(params[1].position, 3);
+    }
+
+    #[test]
+    fn test_parameter_spans_rewritten_tokens() {
+        use crate::config::TemplateMinerConfig;
+        use crate::template_miner::TemplateMiner;
+        use crate::tokenizer::{self, Tokenizer, TokenizerConfig};
+        use std::sync::Arc;
+
+        #[derive(Debug)]
+        struct LowercaseTokenizer;
+
+        impl Tokenizer for LowercaseTokenizer {
+            fn tokenize(&self, content: &str) -> Vec<String> {
+                content.split_whitespace().map(str::to_lowercase).collect()
+            }
+        }
+
+        tokenizer::register_tokenizer("lowercase", |_, _| Ok(Arc::new(LowercaseTokenizer)));
+
+        let config = TemplateMinerConfig {
+            tokenizer: TokenizerConfig {
+                kind: "lowercase".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let miner = TemplateMiner::new(&config, None);
+
+        // "İ" lowercases to three bytes, so the lowercased token is longer than its text.
+        let message = "İİ über ÄÖ";
+        let params = miner
+            .extract_parameters("<*> über <*>", message, true)
+            .unwrap();
+        assert_eq!(params.len(), 2);
+        assert_eq!(params[0].value, "i\u{307}i\u{307}");
+        assert_eq!(params[0].span, None);
+        assert_eq!(params[0].original, None);
+        assert_eq!(params[1].value, "äö");
+        assert_eq!(params[1].span, None);
+        assert_eq!(params[1].original, None);
+
+        let params = miner
+            .extract_parameters("<*> <*> äö", message, true)
+            .unwrap();
+        assert_eq!(params[1].value, "über");
+        assert_eq!(&message[params[1].span.clone().unwrap()], "über");
+        assert_eq!(params[1].original, None);
+    }
+
+    #[test]
+    fn test_sim_th_by_length_and_adaptive() {
+        use crate::cluster::SearchStrategy;
+        use crate::config::TemplateMinerConfig;
+        use crate::drain::{Drain, SerializableDrain};
+        use crate::template_miner::TemplateMiner;
+
+        let config = TemplateMinerConfig {
+            drain_sim_th: 0.4,
+            drain_sim_th_by_length: vec![(1, 0.9), (8, 0.5)],
+            ..Default::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+        assert_eq!(miner.drain.sim_th_for(3), 0.9);
+        assert_eq!(miner.drain.sim_th_for(8), 0.5);
+        assert_eq!(miner.drain.sim_th_for(0), 0.4);
+
+        let (short1, _) = miner.add_log_message("job a started");
+        let (short2, _) = miner.add_log_message("job a stopped");
+        assert_ne!(
+            short1.unwrap().lock().unwrap().cluster_id,
+            short2.unwrap().lock().unwrap().cluster_id
+        );
+        let (long1, _) = miner.add_log_message("job a started on node 1 in zone x");
+        let long1_id = long1.unwrap().lock().unwrap().cluster_id;
+        let (long2, _) = miner.add_log_message("job a stopped on node 2 in zone y");
+        assert_eq!(long2.unwrap().lock().unwrap().cluster_id, long1_id);
+
+        for adaptive in [false, true] {
+            let config = TemplateMinerConfig {
+                drain_sim_th: 0.4,
+                drain_adaptive_sim_th: adaptive,
+                ..Default::default()
+            };
+            let mut miner = TemplateMiner::new(&config, None);
+            let mut stable_id = 0;
+            for _ in 0..11 {
+                let (cluster, _) = miner.add_log_message("cache warmed up fully");
+                stable_id = cluster.unwrap().lock().unwrap().cluster_id;
+            }
+            let (cluster, _) = miner.add_log_message("cache warmed down badly");
+            let cluster_id = cluster.unwrap().lock().unwrap().cluster_id;
+            assert_eq!(cluster_id == stable_id, !adaptive);
+
+            if adaptive {
+                let state = serde_json::to_vec(&SerializableDrain::from(&miner.drain)).unwrap();
+                let restored =
+                    Drain::from(serde_json::from_slice::<SerializableDrain>(&state).unwrap());
+                let cluster = restored
+                    .match_cluster("cache warmed up fully", SearchStrategy::Fast)
+                    .unwrap();
+                let cluster = cluster.lock().unwrap();
+                assert!((cluster.sim_th.unwrap() - 0.6).abs() < 1e-9);
+                assert_eq!(cluster.similarity_stats.count, 10);
+                assert_eq!(cluster.similarity_stats.mean, 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_template_split() {
+        use crate::config::TemplateMinerConfig;
+        use crate::drain::TemplateSplitConfig;
+        use crate::template_miner::TemplateMiner;
+
+        let config = TemplateMinerConfig {
+            drain_template_split: Some(TemplateSplitConfig {
+                max_values: 3,
+                min_value_count: 3,
+            }),
+            ..Default::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+
+        let mut original_id = 0;
+        for disk in ["sda", "sdb", "sda", "sdb", "sda"] {
+            let (cluster, update_type) = miner.add_log_message(&format!("disk {} failed", disk));
+            assert_ne!(update_type, UpdateType::Split);
+            original_id = cluster.unwrap().lock().unwrap().cluster_id;
+        }
+        assert!(miner.drain.take_split_events().is_empty());
+
+        let (cluster, update_type) = miner.add_log_message("disk sdb failed");
+        assert_eq!(update_type, UpdateType::Split);
+        let cluster = cluster.unwrap();
+        let split_id = cluster.lock().unwrap().cluster_id;
+        assert_ne!(split_id, original_id);
+        assert_eq!(cluster.lock().unwrap().get_template(), "disk sdb failed");
+        assert_eq!(cluster.lock().unwrap().size, 3);
+
+        let events = miner.drain.take_split_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].cluster_id, original_id);
+        assert_eq!(events[0].position, 1);
+        assert_eq!(
+            events[0].values,
+            vec![
+                ("sda".to_string(), original_id),
+                ("sdb".to_string(), split_id)
+            ]
+        );
+
+        let (cluster, update_type) = miner.add_log_message("disk sda failed");
+        assert_eq!(update_type, UpdateType::None);
+        let cluster = cluster.unwrap();
+        assert_eq!(cluster.lock().unwrap().cluster_id, original_id);
+        assert_eq!(cluster.lock().unwrap().get_template(), "disk sda failed");
+        let (cluster, _) = miner.add_log_message("disk sdb failed");
+        assert_eq!(cluster.unwrap().lock().unwrap().cluster_id, split_id);
+
+        for user in 0..20 {
+            let (_, update_type) = miner.add_log_message(&format!("user u{} logged in", user % 5));
+            assert_ne!(update_type, UpdateType::Split);
+        }
+        assert!(miner.drain.take_split_events().is_empty());
+    }
+
+    #[test]
+    fn test_template_split_reuses_clusters() {
+        use crate::cluster::LogCluster;
+        use crate::config::TemplateMinerConfig;
+        use crate::drain::{SplitEvent, TemplateSplitConfig};
+        use crate::template_miner::TemplateMiner;
+
+        let config = TemplateMinerConfig {
+            drain_template_split: Some(TemplateSplitConfig {
+                max_values: 3,
+                min_value_count: 3,
+            }),
+            ..Default::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+        miner.drain = with_cluster_ids_from(&miner.drain, 4201);
+
+        for disk in ["sdb", "sdc", "sdb", "sdc", "sdb", "sdc"] {
+            miner.add_log_message(&format!("disk {} failed now", disk));
+        }
+        // A new value generalizes a split cluster again, which then takes the
+        // messages of the other split cluster too, until it is split once more.
+        for disk in ["sdd", "sdb", "sdd", "sdb", "sdd", "sdb"] {
+            miner.add_log_message(&format!("disk {} failed now", disk));
+        }
+
+        let events = miner.drain.take_split_events();
+        assert_eq!(events.len(), 2);
+        for event in &events {
+            for (value, cluster_id) in &event.values {
+                let cluster = LogCluster::get_cluster_by_id(cluster_id).unwrap();
+                assert_eq!(
+                    cluster.lock().unwrap().get_template(),
+                    format!("disk {} failed now", value)
+                );
+            }
+        }
+        assert_eq!(
+            events[0],
+            SplitEvent {
+                cluster_id: 4201,
+                position: 1,
+                values: vec![("sdb".to_string(), 4201), ("sdc".to_string(), 4202)],
+            }
+        );
+        assert_eq!(
+            events[1],
+            SplitEvent {
+                cluster_id: 4202,
+                position: 1,
+                values: vec![
+                    ("sdb".to_string(), 4201),
+                    ("sdc".to_string(), 4202),
+                    ("sdd".to_string(), 4203)
+                ],
+            }
+        );
+
+        let mut clusters: Vec<(String, usize)> = LogCluster::get_clusters()
+            .into_iter()
+            .filter(|c| c.cluster_id > 4200 && c.cluster_id < 4300)
+            .map(|c| (c.get_template(), c.size))
+            .collect();
+        clusters.sort();
+        assert_eq!(
+            clusters,
+            vec![
+                ("disk sdb failed now".to_string(), 6),
+                ("disk sdc failed now".to_string(), 3),
+                ("disk sdd failed now".to_string(), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_template_split_routing() {
+        use crate::config::TemplateMinerConfig;
+        use crate::drain::TemplateSplitConfig;
+        use crate::template_miner::TemplateMiner;
+
+        // The split position is routed by the tree, so the split clusters must
+        // be reachable from the branch of their value once one is opened.
+        let config = TemplateMinerConfig {
+            drain_max_children: 2,
+            drain_template_split: Some(TemplateSplitConfig {
+                max_values: 3,
+                min_value_count: 3,
+            }),
+            ..Default::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+
+        let mut update_type = UpdateType::None;
+        for disk in ["sdb", "sdc", "sdb", "sdc", "sdb", "sdc"] {
+            update_type = miner.add_log_message(&format!("{} disk failed", disk)).1;
+        }
+        assert_eq!(update_type, UpdateType::Split);
+        let split_id = miner.drain.take_split_events()[0].values[1].1;
+
+        miner.add_log_message("sdc other thing");
+        let (cluster, update_type) = miner.add_log_message("sdc disk failed");
+        assert_eq!(update_type, UpdateType::None);
+        let cluster = cluster.unwrap();
+        assert_eq!(cluster.lock().unwrap().cluster_id, split_id);
+        assert_eq!(cluster.lock().unwrap().get_template(), "sdc disk failed");
+    }
+
+    #[test]
+    fn test_cluster_merge() {
+        use crate::cluster::{LogCluster, SearchStrategy};
+        use crate::config::TemplateMinerConfig;
+        use crate::drain::{ClusterMergeConfig, MergeEvent};
+        use crate::template_miner::TemplateMiner;
+
+        let config = TemplateMinerConfig {
+            drain_cluster_merge: Some(ClusterMergeConfig {
+                sim_th: 0.8,
+                interval: 0,
+            }),
+            ..Default::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+        miner.drain = with_cluster_ids_from(&miner.drain, 4301);
+
+        // The first token routes each user to a branch of their own.
+        let (alice, _) = miner.add_log_message("alice logged in from web");
+        let (bob, _) = miner.add_log_message("bob logged in from web");
+        miner.add_log_message("bob logged in from web");
+        let alice_id = alice.unwrap().lock().unwrap().cluster_id;
+        let bob_id = bob.unwrap().lock().unwrap().cluster_id;
+        assert_ne!(alice_id, bob_id);
+        assert!(miner.add_log_message("disk full on sda").1 == UpdateType::Created);
+
+        let events = miner.merge_clusters();
+        assert_eq!(
+            events,
+            vec![MergeEvent {
+                cluster_id: bob_id,
+                merged_cluster_id: alice_id,
+            }]
+        );
+        let matched = miner.match_cluster("alice logged in from web", SearchStrategy::Full);
+        assert_eq!(matched.unwrap().lock().unwrap().cluster_id, bob_id);
+        assert!(LogCluster::get_cluster_by_id(&alice_id).is_none());
+        assert!(
+            !LogCluster::get_clusters()
+                .iter()
+                .any(|c| c.cluster_id == alice_id)
+        );
+
+        let cluster = miner
+            .match_cluster("carol logged in from web", SearchStrategy::Fast)
+            .unwrap();
+        assert_eq!(cluster.lock().unwrap().cluster_id, bob_id);
+        assert_eq!(
+            cluster.lock().unwrap().get_template(),
+            "<TOKEN1> logged in from web"
+        );
+        assert_eq!(cluster.lock().unwrap().size, 3);
+
+        let (cluster, update_type) = miner.add_log_message("dave logged in from web");
+        assert_eq!(update_type, UpdateType::None);
+        assert_eq!(cluster.unwrap().lock().unwrap().cluster_id, bob_id);
+        assert!(miner.merge_clusters().is_empty());
+
+        let config = TemplateMinerConfig {
+            drain_cluster_merge: Some(ClusterMergeConfig {
+                sim_th: 0.8,
+                interval: 2,
+            }),
+            ..Default::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+        let (alice, _) = miner.add_log_message("alice logged out from web");
+        let alice_id = alice.unwrap().lock().unwrap().cluster_id;
+        let (cluster, _) = miner.add_log_message("bob logged out from web");
+        let cluster = cluster.unwrap();
+        assert_eq!(cluster.lock().unwrap().cluster_id, alice_id);
+        assert_eq!(
+            cluster.lock().unwrap().get_template(),
+            "<TOKEN1> logged out from web"
+        );
+        assert_eq!(miner.drain.take_merge_events().len(), 1);
+    }
+
+    #[test]
+    fn test_param_type_inference() {
+        use crate::cluster::SearchStrategy;
+        use crate::config::TemplateMinerConfig;
+        use crate::drain::{Drain, SerializableDrain};
+        use crate::param_type::ParamType;
+        use crate::template_miner::TemplateMiner;
+
+        assert_eq!(ParamType::of("-42"), ParamType::Integer);
+        assert_eq!(ParamType::of("3.14"), ParamType::Float);
+        assert_eq!(ParamType::of("192.168.0.1:8080"), ParamType::Ip);
+        assert_eq!(ParamType::of("fe80::1"), ParamType::Ip);
+        assert_eq!(ParamType::of("0x1f"), ParamType::Hex);
+        assert_eq!(ParamType::of("deadbeef01"), ParamType::Hex);
+        assert_eq!(ParamType::of("deadbeef"), ParamType::Text);
+        assert_eq!(
+            ParamType::of("123e4567-e89b-12d3-a456-426614174000"),
+            ParamType::Uuid
+        );
+        assert_eq!(ParamType::of("2024-03-01T12:30:00Z"), ParamType::Timestamp);
+        assert_eq!(ParamType::of("12:30:00.123"), ParamType::Timestamp);
+        assert_eq!(ParamType::of("alice"), ParamType::Text);
+
+        let config = TemplateMinerConfig::default();
+        let mut miner = TemplateMiner::new(&config, None);
+
+        let mut cluster = None;
+        for i in 0..6 {
+            cluster = miner.add_log_message(&format!(
+                "conn 123e4567-e89b-12d3-a456-42661417400{} from 10.0.0.{} status {} bytes {} took {}.5",
+                i,
+                i,
+                ["ok", "fail"][i % 2],
+                i * 100,
+                i
+            ))
+            .0;
+        }
+
+        let cluster = cluster.unwrap().lock().unwrap().clone();
+        assert_eq!(cluster.param_type(0), None);
+        assert_eq!(
+            cluster.param_types().into_iter().collect::<Vec<_>>(),
+            vec![
+                (1, ParamType::Uuid),
+                (3, ParamType::Ip),
+                (5, ParamType::Enum),
+                (7, ParamType::Integer),
+                (9, ParamType::Float),
+            ]
+        );
+
+        let message =
+            "conn 123e4567-e89b-12d3-a456-426614174009 from 10.0.0.9 status ok bytes 900 took 9.5";
+        let parameters = miner
+            .extract_cluster_parameters(&cluster, message, false)
+            .unwrap();
+        assert_eq!(
+            parameters.iter().map(|p| p.param_type).collect::<Vec<_>>(),
+            vec![
+                ParamType::Uuid,
+                ParamType::Ip,
+                ParamType::Enum,
+                ParamType::Integer,
+                ParamType::Float,
+            ]
+        );
+        let parameters = miner
+            .extract_parameters(&cluster.get_template(), message, false)
+            .unwrap();
+        assert_eq!(parameters[2].param_type, ParamType::Text);
+
+        let state = serde_json::to_vec(&SerializableDrain::from(&miner.drain)).unwrap();
+        let restored = Drain::from(serde_json::from_slice::<SerializableDrain>(&state).unwrap());
+        let restored_cluster = restored
+            .match_cluster(message, SearchStrategy::Fallback)
+            .unwrap();
+        assert_eq!(
+            restored_cluster.lock().unwrap().param_types(),
+            cluster.param_types()
+        );
+    }
+
+    #[test]
+    fn test_value_stats() {
+        use crate::cluster::SearchStrategy;
+        use crate::config::TemplateMinerConfig;
+        use crate::drain::{Drain, SerializableDrain};
+        use crate::template_miner::TemplateMiner;
+        use crate::value_stats::ValueStatsConfig;
+
+        let config = TemplateMinerConfig {
+            drain_value_stats: Some(ValueStatsConfig {
+                top_k: 2,
+                precision: 10,
+            }),
+            ..Default::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+
+        let hosts = [
+            "web1", "web1", "web1", "web1", "web1", "web2", "web2", "web2", "db1",
+        ];
+        let mut cluster = None;
+        for (i, host) in hosts.iter().enumerate() {
+            cluster = miner
+                .add_log_message(&format!(
+                    "connection refused from {} after {} ms",
+                    host,
+                    (i + 1) * 10
+                ))
+                .0;
+        }
+        let cluster = cluster.unwrap();
+        {
+            let cluster = cluster.lock().unwrap();
+            assert_eq!(
+                cluster.get_template(),
+                "connection refused from <TOKEN2> after <TOKEN1> ms"
+            );
+            assert!(cluster.position_stats(0).is_none());
+
+            let host_stats = cluster.position_stats(3).unwrap();
+            assert_eq!(host_stats.count, 9);
+            assert_eq!(host_stats.distinct_count(), 3);
+            assert_eq!(
+                host_stats.top_values(),
+                vec![("web1".to_string(), 5), ("web2".to_string(), 3)]
+            );
+            assert!(host_stats.numeric.is_none());
+
+            let numeric = cluster.position_stats(5).unwrap().numeric.unwrap();
+            assert_eq!(numeric.count, 9);
+            assert_eq!(numeric.min, 10.0);
+            assert_eq!(numeric.max, 90.0);
+            assert!((numeric.mean - 50.0).abs() < 1e-9);
+        }
+
+        for i in 0..1000 {
+            miner.add_log_message(&format!("connection refused from batch{} after 5 ms", i));
+        }
+        {
+            let cluster = cluster.lock().unwrap();
+            let host_stats = cluster.position_stats(3).unwrap();
+            assert_eq!(host_stats.count, 1009);
+            assert_eq!(host_stats.top_values().len(), 2);
+            let distinct = host_stats.distinct_count() as f64;
+            assert!((distinct - 1003.0).abs() < 100.0, "{}", distinct);
+        }
+
+        let state = serde_json::to_vec(&SerializableDrain::from(&miner.drain)).unwrap();
+        let restored = Drain::from(serde_json::from_slice::<SerializableDrain>(&state).unwrap());
+        let restored_cluster = restored
+            .match_cluster(
+                "connection refused from web1 after 10 ms",
+                SearchStrategy::Fallback,
+            )
+            .unwrap();
+        assert_eq!(
+            restored_cluster.lock().unwrap().position_stats(3),
+            cluster.lock().unwrap().position_stats(3)
+        );
+    }
+
+    #[test]
+    fn test_routing_predicates() {
+        use crate::config::TemplateMinerConfig;
+        use crate::routing::{RoutingPredicateConfig, RoutingPredicates};
+        use crate::template_miner::TemplateMiner;
+        use crate::tokenizer::WhitespaceTokenizer;
+
+        let tokenizer = WhitespaceTokenizer::default();
+        let numeric = RoutingPredicates::new(&[], true).unwrap();
+        assert!(numeric.matches("web1", &tokenizer));
+        assert!(!numeric.matches("web", &tokenizer));
+
+        let long_token = RoutingPredicateConfig {
+            min_length: 8,
+            ..RoutingPredicateConfig::new("long_token")
+        };
+        let regex = RoutingPredicateConfig {
+            pattern: "req-[a-z]+".to_string(),
+            ..RoutingPredicateConfig::new("regex")
+        };
+        let predicates = RoutingPredicates::new(
+            &[
+                RoutingPredicateConfig::new("hex_like"),
+                RoutingPredicateConfig::new("path_like"),
+                long_token,
+                regex,
+            ],
+            false,
+        )
+        .unwrap();
+        assert!(!predicates.matches("web1", &tokenizer));
+        assert!(predicates.matches("0xff", &tokenizer));
+        assert!(predicates.matches("123e4567-e89b-12d3-a456-426614174000", &tokenizer));
+        assert!(predicates.matches("/var/log/syslog", &tokenizer));
+        assert!(!predicates.matches("/", &tokenizer));
+        assert!(predicates.matches("abcdefgh", &tokenizer));
+        assert!(!predicates.matches("abcdefg", &tokenizer));
+        assert!(predicates.matches("req-abc", &tokenizer));
+        assert!(!predicates.matches("xreq-ab", &tokenizer));
+        assert!(RoutingPredicates::new(&[RoutingPredicateConfig::new("fancy")], false).is_err());
+
+        // Paths route by their first token, each to a branch of its own.
+        let config = TemplateMinerConfig::default();
+        let mut miner = TemplateMiner::new(&config, None);
+        assert_eq!(
+            miner.add_log_message("/var/log/a opened").1,
+            UpdateType::Created
+        );
+        assert_eq!(
+            miner.add_log_message("/var/log/b opened").1,
+            UpdateType::Created
+        );
+
+        let config = TemplateMinerConfig {
+            drain_routing_predicates: vec![RoutingPredicateConfig::new("path_like")],
+            ..Default::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+        assert_eq!(
+            miner.add_log_message("/var/log/a opened").1,
+            UpdateType::Created
+        );
+        let (cluster, update_type) = miner.add_log_message("/var/log/b opened");
+        assert_eq!(update_type, UpdateType::Updated);
+        assert_eq!(
+            cluster.unwrap().lock().unwrap().get_template(),
+            "<TOKEN1> opened"
+        );
+    }
+
+    #[test]
+    fn test_mask_tokens_as_typed_wildcards() {
+        use crate::cluster::SearchStrategy;
+        use crate::config::TemplateMinerConfig;
+        use crate::template_miner::TemplateMiner;
+
+        let config = TemplateMinerConfig::default();
+        let mut miner = TemplateMiner::new(&config, None);
+
+        let (cluster, update_type) = miner.add_log_message("login from <IP> port <NUM>");
+        assert_eq!(update_type, UpdateType::Created);
+        let cluster_id = cluster.unwrap().lock().unwrap().cluster_id;
+
+        let matched = miner.match_cluster("login from <IP> port <NUM>", SearchStrategy::Fast);
+        assert_eq!(matched.unwrap().lock().unwrap().cluster_id, cluster_id);
+        // A typed wildcard only stands for its own kind.
+        assert!(
+            miner
+                .match_cluster("login from <NUM> port <NUM>", SearchStrategy::Fast)
+                .is_none()
+        );
+
+        let (cluster, update_type) = miner.add_log_message("login from <NUM> port <NUM>");
+        assert_eq!(update_type, UpdateType::Updated);
+        assert_eq!(
+            cluster.unwrap().lock().unwrap().get_template(),
+            "login from <TOKEN1> port <NUM>"
+        );
+        let matched = miner.match_cluster("login from <IP> port <NUM>", SearchStrategy::Fast);
+        assert_eq!(matched.unwrap().lock().unwrap().cluster_id, cluster_id);
+
+        // Masked first tokens share the wildcard branch instead of each having one.
+        assert_eq!(
+            miner.add_log_message("<NUM> items sold").1,
+            UpdateType::Created
+        );
+        let (cluster, update_type) = miner.add_log_message("<HEX> items sold");
+        assert_eq!(update_type, UpdateType::Updated);
+        assert_eq!(
+            cluster.unwrap().lock().unwrap().get_template(),
+            "<TOKEN2> items sold"
+        );
+        let matched = miner.match_cluster("42 items sold", SearchStrategy::Fast);
+        assert!(matched.is_some());
+    }
+
+    #[test]
+    fn test_batch_mining() {
+        use crate::cluster::{LogCluster, SearchStrategy};
+        use crate::config::TemplateMinerConfig;
+        use crate::template_miner::TemplateMiner;
+
+        let corpus = [
+            "user alice logged in",
+            "user bob logged in",
+            "disk sda full",
+            "user alice logged out",
+            "disk sdb full",
+            "user alice logged in",
+        ];
+
+        let config = TemplateMinerConfig::default();
+        let mine = |messages: Vec<&'static str>| {
+            let mut miner = TemplateMiner::new(&config, None);
+            let clusters = miner.rebuild_from_batch(&messages);
+            messages
+                .into_iter()
+                .zip(clusters)
+                .map(|(message, cluster)| {
+                    let cluster = cluster.unwrap();
+                    let cluster = cluster.lock().unwrap();
+                    (message, cluster.get_template(), cluster.size)
+                })
+                .collect::<std::collections::BTreeSet<_>>()
+        };
+
+        let forward = mine(corpus.to_vec());
+        let mut reversed = corpus.to_vec();
+        reversed.reverse();
+        let mut rotated = corpus.to_vec();
+        rotated.rotate_left(3);
+        assert_eq!(mine(reversed), forward);
+        assert_eq!(mine(rotated), forward);
+
+        assert!(forward.contains(&("disk sda full", "disk <TOKEN1> full".to_string(), 2)));
+        assert!(forward.contains(&(
+            "user bob logged in",
+            "user <TOKEN3> logged <TOKEN2>".to_string(),
+            4
+        )));
+
+        // Rebuilding discards what was mined before, ids and counter included.
+        let mut miner = TemplateMiner::new(&config, None);
+        miner.drain = with_cluster_ids_from(&miner.drain, 4401);
+        miner.add_log_message("service web1 started");
+        miner.add_log_message("service web2 started");
+        miner.add_log_message("disk sdc full");
+        assert!(LogCluster::get_cluster_by_id(&4401).is_some());
+        let clusters = miner.rebuild_from_batch(&["disk sda full", "disk sdb full"]);
+        let cluster = clusters[0].as_ref().unwrap().lock().unwrap().clone();
+        assert_eq!(cluster.cluster_id, 1);
+        assert_eq!(cluster.get_template(), "disk <TOKEN1> full");
+        assert_eq!(cluster.size, 2);
+        let hierarchy = miner.drain.template_hierarchy();
+        assert_eq!(hierarchy.roots().len(), 1);
+        assert_eq!(hierarchy.roots()[0].total_size(), 2);
+        assert!(
+            miner
+                .match_cluster("service web1 started", SearchStrategy::Fast)
+                .is_none()
+        );
+        assert!(LogCluster::get_cluster_by_id(&4401).is_none());
+        assert!(LogCluster::get_cluster_by_id(&4402).is_none());
+    }
+
+    #[test]
+    fn test_template_hierarchy() {
+        use crate::config::TemplateMinerConfig;
+        use crate::template_miner::TemplateMiner;
+
+        let config = TemplateMinerConfig {
+            drain_depth: 5,
+            ..TemplateMinerConfig::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+
+        let admin = miner.add_log_message("user admin logged in").0.unwrap();
+        let admin_id = admin.lock().unwrap().cluster_id;
+        // Numbered users route to the wildcard branch and generalize there.
+        miner.add_log_message("user u1 logged in");
+        let users = miner.add_log_message("user u2 logged in").0.unwrap();
+        let users_id = users.lock().unwrap().cluster_id;
+        assert_eq!(
+            users.lock().unwrap().get_template(),
+            "user <TOKEN1> logged in"
+        );
+        let disk = miner.add_log_message("disk full").0.unwrap();
+        let disk_id = disk.lock().unwrap().cluster_id;
+
+        let hierarchy = miner.drain.template_hierarchy();
+        let roots: Vec<usize> = hierarchy.roots().iter().map(|n| n.cluster_id).collect();
+        assert_eq!(roots, vec![users_id, disk_id]);
+
+        let users = hierarchy.get(users_id).unwrap();
+        assert_eq!(users.children.len(), 1);
+        assert_eq!(users.children[0].template, "user admin logged in");
+        assert_eq!(users.total_size(), 3);
+        assert_eq!(hierarchy.parent(admin_id).unwrap().cluster_id, users_id);
+        assert!(hierarchy.parent(disk_id).is_none());
+        let path: Vec<usize> = hierarchy
+            .path(admin_id)
+            .iter()
+            .map(|n| n.cluster_id)
+            .collect();
+        assert_eq!(path, vec![users_id, admin_id]);
+
+        let json: serde_json::Value = serde_json::from_str(&hierarchy.to_json().unwrap()).unwrap();
+        assert_eq!(json[0]["template"], "user <TOKEN1> logged in");
+        assert_eq!(json[0]["children"][0]["template"], "user admin logged in");
+        assert_eq!(json[1]["children"].as_array().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_template_history() {
+        use crate::cluster::SearchStrategy;
+        use crate::config::TemplateMinerConfig;
+        use crate::drain::{Drain, SerializableDrain};
+        use crate::template_miner::TemplateMiner;
+
+        let config = TemplateMinerConfig {
+            drain_template_history: 2,
+            ..TemplateMinerConfig::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+
+        let cluster = miner.add_log_message("user alice logged in").0.unwrap();
+        assert_eq!(cluster.lock().unwrap().history.len(), 1);
+        miner.add_log_message("user bob logged in");
+        miner.add_log_message("user bob logged out");
+        // Messages leaving the template as is add no version.
+        assert_eq!(
+            miner.add_log_message("user carol logged out").1,
+            UpdateType::None
+        );
+
+        let cluster = cluster.lock().unwrap().clone();
+        let history: Vec<(&str, usize, Option<&str>)> = cluster
+            .history
+            .iter()
+            .map(|v| (v.template.as_str(), v.size, v.message.as_deref()))
+            .collect();
+        assert_eq!(
+            history,
+            vec![
+                ("user <TOKEN1> logged in", 2, Some("user bob logged in")),
+                (
+                    "user <TOKEN1> logged <TOKEN2>",
+                    3,
+                    Some("user bob logged out")
+                ),
+            ]
+        );
+        let latest = cluster.history.last().unwrap();
+        assert_eq!(cluster.template_at(latest.timestamp), Some(latest));
+        assert_eq!(cluster.template_at(0), None);
+
+        let state = serde_json::to_vec(&SerializableDrain::from(&miner.drain)).unwrap();
+        let restored = Drain::from(serde_json::from_slice::<SerializableDrain>(&state).unwrap());
+        let restored = restored
+            .match_cluster("user dave logged out", SearchStrategy::Fast)
+            .unwrap();
+        assert_eq!(restored.lock().unwrap().history, cluster.history);
+    }
+
+    #[test]
+    fn test_update_template() {
+        use crate::cluster::LogCluster;
+
+        let tokens = |s: &str| -> Vec<String> { s.split(' ').map(str::to_string).collect() };
+        let mut cluster = LogCluster::new(&tokens("user alice logged <*>"), 1);
+        let mut counter = 0;
+        let update_type = cluster.update_template(
+            &tokens("user bob logged out"),
+            |token| token == "<*>",
+            || {
+                counter += 1;
+                format!("<T{}>", counter)
+            },
+        );
+        assert_eq!(update_type, UpdateType::Updated);
+        assert_eq!(cluster.get_template(), "user <T1> logged <*>");
+        assert_eq!(cluster.size, 2);
+
+        let update_type = cluster.update_template(
+            &tokens("user carol logged in"),
+            |t| t.starts_with('<'),
+            || unreachable!(),
+        );
+        assert_eq!(update_type, UpdateType::None);
+        assert_eq!(cluster.size, 3);
+    }
+
+    #[test]
+    fn test_update_template_of_mined_cluster() {
+        use crate::cluster::SearchStrategy;
+        use std::sync::Arc;
+
+        let mut drain = Drain::new(&crate::drain::DrainConfig {
+            log_cluster_depth: 4,
+            sim_th: 0.4,
+            sim_th_by_length: vec![],
+            adaptive_sim_th: false,
+            max_children: 100,
+            max_clusters: None,
+            extra_delimiters: vec![],
+            parametrize_numeric_tokens: true,
+            routing_predicates: vec![],
+            max_length_difference: 0,
+            template_split: None,
+            cluster_merge: None,
+            value_stats: None,
+            template_history: 0,
+            token_prefix: "<".to_string(),
+            token_suffix: ">".to_string(),
+            token_template: "TOKEN".to_string(),
+        });
+        let cluster = drain.add_log_message("user alice logged out").0.unwrap();
+        let update_type = cluster.lock().unwrap().update_template(
+            &["user", "bob", "logged", "out"].map(String::from),
+            |_| false,
+            || "<*>".to_string(),
+        );
+        assert_eq!(update_type, UpdateType::Updated);
+
+        // The changed position matches any token, and stays a parameter once the
+        // drain updates the cluster again.
+        let (matched, update_type) = drain.add_log_message("user carol logged out");
+        assert!(Arc::ptr_eq(&matched.unwrap(), &cluster));
+        assert_eq!(update_type, UpdateType::None);
+        assert_eq!(
+            cluster.lock().unwrap().get_template(),
+            "user <*> logged out"
+        );
+        assert!(
+            drain
+                .match_cluster("user dave logged out", SearchStrategy::Fast)
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_variable_length_mixed_templates() {
+        use crate::config::TemplateMinerConfig;
+        use crate::template_miner::TemplateMiner;
+
+        let config = TemplateMinerConfig {
+            drain_max_length_difference: 2,
+            ..Default::default()
+        };
+        let mut miner = TemplateMiner::new(&config, None);
+
+        let users = ["root", "admin", "oracle", "test", "guest"];
+        let mut clusters = std::collections::BTreeMap::new();
+        for i in 0..50 {
+            let user = users[i % users.len()];
+            let host = format!("h{}", i);
+            let messages = [
+                format!("Failed password for {} from {} port {} ssh2", user, host, i),
+                format!(
+                    "Failed password for invalid user {} from {} port {} ssh2",
+                    user, host, i
+                ),
+                format!("Accepted password for {} from {}", user, host),
+                format!("Received disconnect from {}: 11: Bye Bye [preauth]", host),
+                format!("Connection closed by {} [preauth]", host),
+                format!("Invalid user {} from {}", user, host),
+                format!("pam_unix(sshd:auth): check pass; user {} unknown", user),
+                format!("input_userauth_request: invalid user {} [preauth]", user),
+            ];
+            for message in &messages {
+                let cluster = miner.add_log_message(message).0.unwrap();
+                let cluster_id = cluster.lock().unwrap().cluster_id;
+                clusters.insert(cluster_id, cluster);
+            }
+        }
+
+        // Loose merges used to leave a template of wildcards taking in everything.
+        assert!(clusters.len() >= 6);
+        for cluster in clusters.values() {
+            let tokens = cluster.lock().unwrap().get_tokens();
+            let literals = tokens.iter().filter(|t| !t.starts_with('<')).count();
+            assert!(literals >= 2, "{:?}", tokens);
+        }
+    }
 }
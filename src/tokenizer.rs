@@ -0,0 +1,460 @@
+use anyhow::{Result, anyhow, bail};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, LazyLock, Mutex};
+use unicode_segmentation::UnicodeSegmentation;
+
+pub trait Tokenizer: Debug + Send + Sync {
+    fn tokenize(&self, content: &str) -> Vec<String>;
+    /// Tokens ending with this separator are keys, joined to their value without a space;
+    /// the value after a key is never a key itself, even if it ends with the separator.
+    fn key_value_separator(&self) -> Option<&str> {
+        None
+    }
+    /// Whether the token contains a digit, routing it to the wildcard branch of the prefix tree.
+    fn has_numbers(&self, token: &str) -> bool {
+        token.chars().any(|c| c.is_ascii_digit())
+    }
+}
+
+/// Joins tokens with spaces, except after key tokens ending with `key_value_separator`.
+/// A token right after a key is its value, so it is not glued to the next one.
+pub fn join_tokens(tokens: &[String], key_value_separator: Option<&str>) -> String {
+    if key_value_separator.is_none() {
+        return tokens.join(" ");
+    }
+    join_tokens_with_offsets(tokens, key_value_separator).0
+}
+
+/// Like `join_tokens`, also returning the byte offset of each token in the result.
+pub fn join_tokens_with_offsets(
+    tokens: &[String],
+    key_value_separator: Option<&str>,
+) -> (String, Vec<usize>) {
+    let mut joined = String::new();
+    let mut offsets = Vec::with_capacity(tokens.len());
+    let mut after_key = false;
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 && !after_key {
+            joined.push(' ');
+        }
+        offsets.push(joined.len());
+        joined.push_str(token);
+        after_key =
+            !after_key && key_value_separator.is_some_and(|separator| token.ends_with(separator));
+    }
+    (joined, offsets)
+}
+
+pub type TokenizerFactory = fn(&TokenizerConfig, &[String]) -> Result<Arc<dyn Tokenizer>>;
+
+static TOKENIZER_REGISTRY: LazyLock<Mutex<HashMap<String, TokenizerFactory>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenizerConfig {
+    /// `whitespace`, `regex_delimiter`, `quote_aware`, `key_value`, `unicode`, or a kind added with `register_tokenizer`.
+    #[serde(default = "default_kind")]
+    pub kind: String,
+    /// Open/close pairs kept together by the `quote_aware` tokenizer; quotes do not nest.
+    #[serde(default = "default_quote_pairs")]
+    pub quote_pairs: Vec<(String, String)>,
+    /// Open/close pairs kept together by the `quote_aware` tokenizer; brackets nest.
+    #[serde(default = "default_bracket_pairs")]
+    pub bracket_pairs: Vec<(String, String)>,
+    /// Separator between a key and its value for the `key_value` tokenizer.
+    #[serde(default = "default_key_value_separator")]
+    pub key_value_separator: String,
+}
+
+fn default_kind() -> String {
+    "whitespace".to_string()
+}
+
+fn default_key_value_separator() -> String {
+    "=".to_string()
+}
+
+fn default_quote_pairs() -> Vec<(String, String)> {
+    vec![
+        ("\"".to_string(), "\"".to_string()),
+        ("'".to_string(), "'".to_string()),
+    ]
+}
+
+fn default_bracket_pairs() -> Vec<(String, String)> {
+    vec![
+        ("[".to_string(), "]".to_string()),
+        ("(".to_string(), ")".to_string()),
+        ("{".to_string(), "}".to_string()),
+    ]
+}
+
+impl Default for TokenizerConfig {
+    fn default() -> Self {
+        Self {
+            kind: default_kind(),
+            quote_pairs: default_quote_pairs(),
+            bracket_pairs: default_bracket_pairs(),
+            key_value_separator: default_key_value_separator(),
+        }
+    }
+}
+
+/// Splits on whitespace after replacing each extra delimiter, taken literally, with a space.
+#[derive(Debug, Clone, Default)]
+pub struct WhitespaceTokenizer {
+    extra_delimiters: Vec<String>,
+}
+
+impl WhitespaceTokenizer {
+    pub fn new(extra_delimiters: &[String]) -> Self {
+        Self {
+            extra_delimiters: extra_delimiters.to_vec(),
+        }
+    }
+}
+
+impl Tokenizer for WhitespaceTokenizer {
+    fn tokenize(&self, content: &str) -> Vec<String> {
+        let mut content = content.trim().to_string();
+        for delimiter in &self.extra_delimiters {
+            content = content.replace(delimiter, " ");
+        }
+        content.split_whitespace().map(|s| s.to_string()).collect()
+    }
+}
+
+/// Splits on whitespace after replacing each extra delimiter, compiled as a regex, with a space.
+#[derive(Debug, Clone)]
+pub struct RegexDelimiterTokenizer {
+    delimiters: Vec<Regex>,
+}
+
+impl RegexDelimiterTokenizer {
+    pub fn new(extra_delimiters: &[String]) -> Result<Self> {
+        let delimiters = extra_delimiters
+            .iter()
+            .map(|d| {
+                Regex::new(d).map_err(|e| anyhow!("failed to compile delimiter regex {}, {}", d, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { delimiters })
+    }
+}
+
+impl Tokenizer for RegexDelimiterTokenizer {
+    fn tokenize(&self, content: &str) -> Vec<String> {
+        let mut content = content.trim().to_string();
+        for delimiter in &self.delimiters {
+            content = delimiter.replace_all(&content, " ").into_owned();
+        }
+        content.split_whitespace().map(|s| s.to_string()).collect()
+    }
+}
+
+/// Splits like `WhitespaceTokenizer`, but keeps quoted strings and bracketed groups,
+/// such as `"ls -la /tmp"` or `[a, b, c]`, as single tokens.
+#[derive(Debug, Clone, Default)]
+pub struct QuoteAwareTokenizer {
+    extra_delimiters: Vec<String>,
+    quote_pairs: Vec<(String, String)>,
+    bracket_pairs: Vec<(String, String)>,
+}
+
+impl QuoteAwareTokenizer {
+    pub fn new(
+        extra_delimiters: &[String],
+        quote_pairs: &[(String, String)],
+        bracket_pairs: &[(String, String)],
+    ) -> Result<Self> {
+        for (open, close) in quote_pairs.iter().chain(bracket_pairs.iter()) {
+            if open.is_empty() || close.is_empty() {
+                bail!("tokenizer group pair must not be empty");
+            }
+        }
+        Ok(Self {
+            extra_delimiters: extra_delimiters
+                .iter()
+                .filter(|d| !d.is_empty())
+                .cloned()
+                .collect(),
+            quote_pairs: quote_pairs.to_vec(),
+            bracket_pairs: bracket_pairs.to_vec(),
+        })
+    }
+
+    fn starts_with_any<'p>(
+        rest: &str,
+        pairs: &'p [(String, String)],
+    ) -> Option<&'p (String, String)> {
+        pairs
+            .iter()
+            .find(|(open, _)| rest.starts_with(open.as_str()))
+    }
+
+    fn delimiter_len(&self, rest: &str) -> Option<usize> {
+        let c = rest.chars().next()?;
+        if c.is_whitespace() {
+            return Some(c.len_utf8());
+        }
+        self.extra_delimiters
+            .iter()
+            .find(|d| rest.starts_with(d.as_str()))
+            .map(|d| d.len())
+    }
+
+    /// Splits `content` into `tokens`, taking the group openings at the sorted
+    /// offsets of `plain` as plain characters, and returns the offsets of the groups
+    /// left open at the end of the line.
+    fn scan(&self, content: &str, plain: &[usize], tokens: &mut Vec<String>) -> Vec<usize> {
+        let mut plain = plain.iter().copied().peekable();
+        let mut token_start: Option<usize> = None;
+        // Open groups, outermost first, with their start and closing string.
+        let mut groups: Vec<(usize, &str)> = Vec::new();
+        let mut in_quote = false;
+        // Whether the quote's character at hand follows an odd run of backslashes.
+        let mut escaped = false;
+        let mut prev: Option<char> = None;
+        let mut i = 0;
+
+        while i < content.len() {
+            let rest = &content[i..];
+            let c = rest.chars().next().unwrap();
+
+            if in_quote {
+                let (_, close) = groups[groups.len() - 1];
+                if !escaped && rest.starts_with(close) {
+                    groups.pop();
+                    in_quote = false;
+                    i += close.len();
+                } else {
+                    escaped = !escaped && c == '\\';
+                    i += c.len_utf8();
+                }
+                prev = content[..i].chars().last();
+                continue;
+            }
+
+            if groups.is_empty()
+                && let Some(len) = self.delimiter_len(rest)
+            {
+                if let Some(start) = token_start.take() {
+                    tokens.push(content[start..i].to_string());
+                }
+                i += len;
+                prev = None;
+                continue;
+            }
+
+            token_start.get_or_insert(i);
+            while plain.next_if(|&p| p < i).is_some() {}
+
+            // A quote only opens a group at a word boundary, so `don't` stays one word.
+            let at_boundary = !prev.is_some_and(|p| p.is_alphanumeric());
+            if plain.next_if_eq(&i).is_some() {
+                i += c.len_utf8();
+            } else if at_boundary
+                && let Some((open, close)) = Self::starts_with_any(rest, &self.quote_pairs)
+            {
+                groups.push((i, close.as_str()));
+                in_quote = true;
+                escaped = false;
+                i += open.len();
+            } else if let Some(&(_, close)) = groups.last()
+                && rest.starts_with(close)
+            {
+                groups.pop();
+                i += close.len();
+            } else if let Some((open, close)) = Self::starts_with_any(rest, &self.bracket_pairs) {
+                groups.push((i, close.as_str()));
+                i += open.len();
+            } else {
+                i += c.len_utf8();
+            }
+            prev = content[..i].chars().last();
+        }
+
+        if let Some(start) = token_start {
+            tokens.push(content[start..].to_string());
+        }
+
+        groups.into_iter().map(|(start, _)| start).collect()
+    }
+}
+
+impl Tokenizer for QuoteAwareTokenizer {
+    fn tokenize(&self, content: &str) -> Vec<String> {
+        let content = content.trim();
+        // Groups left open at the end of the line are split like plain text, so the
+        // line is scanned again with their openings taken as plain characters. The
+        // other groups stay as they were, so the second scan is final unless a
+        // delimiter overlaps an opening.
+        let mut plain = Vec::new();
+        loop {
+            let mut tokens = Vec::new();
+            let unclosed = self.scan(content, &plain, &mut tokens);
+            if unclosed.is_empty() {
+                return tokens;
+            }
+            plain.extend(unclosed);
+            plain.sort_unstable();
+        }
+    }
+}
+
+/// Value token of a key with no value, such as `user=` in `user= status=200`; it
+/// renders as `user=""`, which tokenizes the same again. Parameter extraction reports
+/// it as an empty value.
+pub const EMPTY_VALUE: &str = "\"\"";
+
+/// Splits logfmt-style `key=value` tokens into a constant `key=` token and a value
+/// token, so values can be parametrized independently of their keys. Keys with no
+/// value get `EMPTY_VALUE` for value.
+#[derive(Debug, Clone)]
+pub struct KeyValueTokenizer {
+    inner: QuoteAwareTokenizer,
+    separator: String,
+}
+
+impl KeyValueTokenizer {
+    pub fn new(
+        extra_delimiters: &[String],
+        quote_pairs: &[(String, String)],
+        bracket_pairs: &[(String, String)],
+        separator: &str,
+    ) -> Result<Self> {
+        if separator.is_empty() {
+            bail!("key value separator must not be empty");
+        }
+        Ok(Self {
+            inner: QuoteAwareTokenizer::new(extra_delimiters, quote_pairs, bracket_pairs)?,
+            separator: separator.to_string(),
+        })
+    }
+
+    fn is_key(key: &str) -> bool {
+        !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '.' || c == '-')
+    }
+}
+
+impl Tokenizer for KeyValueTokenizer {
+    fn tokenize(&self, content: &str) -> Vec<String> {
+        let mut tokens = Vec::new();
+        for token in self.inner.tokenize(content) {
+            match token.split_once(self.separator.as_str()) {
+                Some((key, value)) if Self::is_key(key) => {
+                    tokens.push(format!("{}{}", key, self.separator));
+                    if value.is_empty() {
+                        tokens.push(EMPTY_VALUE.to_string());
+                    } else {
+                        tokens.push(value.to_string());
+                    }
+                }
+                _ => tokens.push(token),
+            }
+        }
+        tokens
+    }
+
+    fn key_value_separator(&self) -> Option<&str> {
+        Some(&self.separator)
+    }
+}
+
+/// Splits like `WhitespaceTokenizer`, then segments runs of scripts written without
+/// spaces, such as Chinese or Japanese, on Unicode (UAX #29) word boundaries.
+#[derive(Debug, Clone, Default)]
+pub struct UnicodeTokenizer {
+    inner: WhitespaceTokenizer,
+}
+
+impl UnicodeTokenizer {
+    pub fn new(extra_delimiters: &[String]) -> Self {
+        Self {
+            inner: WhitespaceTokenizer::new(extra_delimiters),
+        }
+    }
+
+    fn is_unspaced_script(c: char) -> bool {
+        matches!(c as u32,
+            0x0E00..=0x0EFF // Thai, Lao
+            | 0x1000..=0x109F // Myanmar
+            | 0x1780..=0x17FF // Khmer
+            | 0x3040..=0x30FF // Hiragana, Katakana
+            | 0x3100..=0x312F // Bopomofo
+            | 0x3400..=0x4DBF // CJK Extension A
+            | 0x4E00..=0x9FFF // CJK Unified Ideographs
+            | 0xF900..=0xFAFF // CJK Compatibility Ideographs
+            | 0xFF00..=0xFFEF // Halfwidth and Fullwidth Forms
+            | 0x20000..=0x2FA1F // CJK Extensions B-F, Compatibility Supplement
+        ) || matches!(c, '\u{3000}'..='\u{303F}') // CJK Symbols and Punctuation
+    }
+}
+
+impl Tokenizer for UnicodeTokenizer {
+    fn tokenize(&self, content: &str) -> Vec<String> {
+        let mut tokens = Vec::new();
+        for token in self.inner.tokenize(content) {
+            if token.chars().any(Self::is_unspaced_script) {
+                tokens.extend(
+                    token
+                        .split_word_bounds()
+                        .filter(|w| !w.trim().is_empty())
+                        .map(|w| w.to_string()),
+                );
+            } else {
+                tokens.push(token);
+            }
+        }
+        tokens
+    }
+
+    fn has_numbers(&self, token: &str) -> bool {
+        token.chars().any(|c| c.is_numeric())
+    }
+}
+
+/// Makes a custom tokenizer selectable from config by its `kind`.
+pub fn register_tokenizer(kind: &str, factory: TokenizerFactory) {
+    TOKENIZER_REGISTRY
+        .lock()
+        .unwrap()
+        .insert(kind.to_string(), factory);
+}
+
+pub fn create_tokenizer(
+    config: &TokenizerConfig,
+    extra_delimiters: &[String],
+) -> Result<Arc<dyn Tokenizer>> {
+    match config.kind.as_str() {
+        "whitespace" => Ok(Arc::new(WhitespaceTokenizer::new(extra_delimiters))),
+        "regex_delimiter" => Ok(Arc::new(RegexDelimiterTokenizer::new(extra_delimiters)?)),
+        "quote_aware" => Ok(Arc::new(QuoteAwareTokenizer::new(
+            extra_delimiters,
+            &config.quote_pairs,
+            &config.bracket_pairs,
+        )?)),
+        "unicode" => Ok(Arc::new(UnicodeTokenizer::new(extra_delimiters))),
+        "key_value" => Ok(Arc::new(KeyValueTokenizer::new(
+            extra_delimiters,
+            &config.quote_pairs,
+            &config.bracket_pairs,
+            &config.key_value_separator,
+        )?)),
+        kind => {
+            let factory = TOKENIZER_REGISTRY
+                .lock()
+                .unwrap()
+                .get(kind)
+                .copied()
+                .ok_or_else(|| anyhow!("unknown tokenizer kind {}", kind))?;
+            factory(config, extra_delimiters)
+        }
+    }
+}
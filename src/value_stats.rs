@@ -0,0 +1,240 @@
+use serde::{Deserialize, Serialize};
+
+use crate::param_type::ParamType;
+
+/// Streaming statistics kept for the values of each wildcard position.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValueStatsConfig {
+    /// Heavy hitters reported per position; twice as many are tracked.
+    #[serde(default = "default_top_k")]
+    pub top_k: usize,
+    /// Distinct counts use `2^precision` one-byte registers per position.
+    #[serde(default = "default_precision")]
+    pub precision: u8,
+}
+
+fn default_top_k() -> usize {
+    10
+}
+
+fn default_precision() -> u8 {
+    10
+}
+
+impl Default for ValueStatsConfig {
+    fn default() -> Self {
+        Self {
+            top_k: default_top_k(),
+            precision: default_precision(),
+        }
+    }
+}
+
+/// Statistics of the values taken by one wildcard position.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PositionStats {
+    pub count: usize,
+    pub distinct: HyperLogLog,
+    pub top_k: TopK,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub numeric: Option<NumericStats>,
+}
+
+impl PositionStats {
+    pub fn new(config: &ValueStatsConfig) -> Self {
+        Self {
+            count: 0,
+            distinct: HyperLogLog::new(config.precision),
+            top_k: TopK::new(config.top_k),
+            numeric: None,
+        }
+    }
+
+    /// Records `value` seen `count` times.
+    pub fn add(&mut self, value: &str, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.count += count;
+        self.distinct.add(value);
+        self.top_k.add(value, count);
+        if matches!(ParamType::of(value), ParamType::Integer | ParamType::Float)
+            && let Ok(number) = value.parse::<f64>()
+        {
+            self.numeric
+                .get_or_insert_with(NumericStats::default)
+                .add(number, count);
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.distinct.merge(&other.distinct);
+        self.top_k.merge(&other.top_k);
+        match (&mut self.numeric, &other.numeric) {
+            (Some(numeric), Some(other)) => numeric.merge(other),
+            (None, Some(other)) => self.numeric = Some(*other),
+            _ => {}
+        }
+    }
+
+    /// Approximate number of distinct values.
+    pub fn distinct_count(&self) -> usize {
+        self.distinct.estimate()
+    }
+
+    /// Most frequent values with their approximate counts, most frequent first.
+    pub fn top_values(&self) -> Vec<(String, usize)> {
+        self.top_k.top()
+    }
+}
+
+/// Minimum, maximum and mean of the numeric values of a position.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct NumericStats {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+impl NumericStats {
+    fn add(&mut self, value: f64, count: usize) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        }
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.count += count;
+        self.mean += (value - self.mean) * count as f64 / self.count as f64;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            *self = *other;
+            return;
+        }
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.count += other.count;
+        self.mean += (other.mean - self.mean) * other.count as f64 / self.count as f64;
+    }
+}
+
+/// HyperLogLog sketch of the distinct values seen.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new(precision: u8) -> Self {
+        Self {
+            registers: vec![0; 1 << precision.clamp(4, 16)],
+        }
+    }
+
+    pub fn add(&mut self, value: &str) {
+        let hash = hash(value);
+        let precision = self.registers.len().trailing_zeros();
+        let index = (hash >> (64 - precision)) as usize;
+        let rank = ((hash << precision) | (1 << (precision - 1))).leading_zeros() as u8 + 1;
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    /// Takes the union with a sketch of the same precision; others are ignored.
+    pub fn merge(&mut self, other: &Self) {
+        if self.registers.len() != other.registers.len() {
+            return;
+        }
+        for (register, &other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(other);
+        }
+    }
+
+    pub fn estimate(&self) -> usize {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        // Small cardinalities are better counted from the empty registers.
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            return (m * (m / zeros as f64).ln()).round() as usize;
+        }
+        estimate.round() as usize
+    }
+}
+
+/// 64-bit FNV-1a finished with the SplitMix64 mixer, stable across runs so
+/// persisted sketches keep counting the same values the same way.
+fn hash(value: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in value.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// Space-Saving heavy hitters: a value evicting the least frequent counter
+/// inherits its count, so counts may be overestimated but never missed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopK {
+    k: usize,
+    counters: Vec<(String, usize)>,
+}
+
+impl TopK {
+    pub fn new(k: usize) -> Self {
+        Self {
+            k,
+            counters: Vec::new(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        2 * self.k
+    }
+
+    pub fn add(&mut self, value: &str, count: usize) {
+        if let Some(counter) = self.counters.iter_mut().find(|(v, _)| v == value) {
+            counter.1 += count;
+            return;
+        }
+        if self.counters.len() < self.capacity() {
+            self.counters.push((value.to_string(), count));
+            return;
+        }
+        if let Some(min) = self.counters.iter_mut().min_by_key(|(_, c)| *c) {
+            *min = (value.to_string(), min.1 + count);
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (value, count) in &other.counters {
+            self.add(value, *count);
+        }
+    }
+
+    pub fn top(&self) -> Vec<(String, usize)> {
+        let mut top = self.counters.clone();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(self.k);
+        top
+    }
+}